@@ -0,0 +1,218 @@
+use crate::error::Error;
+use anyhow::{bail, Result};
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap},
+};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::warn;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// May prepare and submit purchases.
+    Client,
+    /// May also manage vendors and read the purchase ledger.
+    Admin,
+}
+
+/// Api tokens accepted by the server, only their sha256 hashes are kept.
+#[derive(Debug)]
+pub struct Auth {
+    admin: Vec<String>,
+    client: Vec<String>,
+}
+
+pub type SharedAuth = Arc<Auth>;
+
+impl Auth {
+    /// Fails if a role has no configured token, unless `insecure_no_auth`
+    /// asks to leave its routes open.
+    pub fn new(
+        admin_token_hashes: &[String],
+        client_token_hashes: &[String],
+        insecure_no_auth: bool,
+    ) -> Result<Self> {
+        let parse = |hashes: &[String]| {
+            hashes
+                .iter()
+                .map(|hash| {
+                    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                        bail!("token hash {hash} is not a hex encoded sha256 digest");
+                    }
+                    Ok(hash.to_ascii_lowercase())
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let auth = Self {
+            admin: parse(admin_token_hashes)?,
+            client: parse(client_token_hashes)?,
+        };
+        for (role, name, open) in [
+            (Role::Admin, "admin", "anyone can manage vendors"),
+            (Role::Client, "client", "anyone can submit purchases"),
+        ] {
+            if !auth.tokens(role).is_empty() {
+                continue;
+            }
+            if !insecure_no_auth {
+                bail!(
+                    "no {name} token configured, set auth.{name}_token_hashes or pass \
+                     --insecure-no-auth to leave its routes open"
+                );
+            }
+            warn!("no {name} token configured, {open}");
+        }
+        Ok(auth)
+    }
+
+    fn tokens(&self, role: Role) -> &[String] {
+        match role {
+            Role::Client => &self.client,
+            Role::Admin => &self.admin,
+        }
+    }
+
+    /// The highest role granted by an api token, if it is one.
+    pub fn role(&self, token: &str) -> Option<Role> {
+        let hash = hash(token);
+        [Role::Admin, Role::Client]
+            .into_iter()
+            .find(|&role| self.tokens(role).contains(&hash))
+    }
+
+    /// Checks the request carries a token granting at least `required`.
+    pub fn authorize(&self, headers: &HeaderMap, required: Role) -> Result<(), Error> {
+        if self.tokens(required).is_empty() {
+            return Ok(());
+        }
+        let token =
+            token(headers).ok_or_else(|| Error::Unauthorized("missing api token".into()))?;
+        let role = self
+            .role(token)
+            .ok_or_else(|| Error::Unauthorized("invalid api token".into()))?;
+        if role < required {
+            return Err(Error::Forbidden(
+                "this token can not access this route".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reads the token from an `X-Api-Key` or, if absent, `Authorization: Bearer`
+/// header, which buyers otherwise use for their session.
+fn token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .or_else(|| bearer(headers))
+}
+
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+pub fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Returns a new random token, to be handed to a client, and its hash, to be
+/// put in the configuration.
+pub fn generate() -> (String, String) {
+    let token = random_token();
+    let hash = hash(&token);
+    (token, hash)
+}
+
+/// 32 random bytes, base58 encoded.
+pub fn random_token() -> String {
+    let mut bytes = [0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bs58::encode(bytes).into_string()
+}
+
+/// Extractor rejecting requests without an admin token.
+pub struct Admin;
+
+/// Extractor rejecting requests without a client or admin token.
+pub struct Client;
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Admin {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Error> {
+        authorize(parts, Role::Admin).map(|_| Self)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Client {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Error> {
+        authorize(parts, Role::Client).map(|_| Self)
+    }
+}
+
+fn authorize(parts: &Parts, required: Role) -> Result<(), Error> {
+    parts
+        .extensions
+        .get::<SharedAuth>()
+        .expect("auth extension is not installed")
+        .authorize(&parts.headers, required)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_roles_without_tokens_unless_insecure() {
+        let admin = [hash("admin")];
+        let client = [hash("client")];
+        assert!(Auth::new(&admin, &client, false).is_ok());
+        assert!(Auth::new(&[], &client, false).is_err());
+        assert!(Auth::new(&admin, &[], false).is_err());
+
+        let auth = Auth::new(&[], &[], true).unwrap();
+        assert!(auth.authorize(&HeaderMap::new(), Role::Admin).is_ok());
+    }
+
+    #[test]
+    fn authorizes_by_role() {
+        let auth = Auth::new(&[hash("admin")], &[hash("client")], false).unwrap();
+        let headers = |token: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(API_KEY_HEADER, token.parse().unwrap());
+            headers
+        };
+        assert!(auth.authorize(&headers("admin"), Role::Admin).is_ok());
+        assert!(auth.authorize(&headers("admin"), Role::Client).is_ok());
+        assert!(auth.authorize(&headers("client"), Role::Client).is_ok());
+        assert!(matches!(
+            auth.authorize(&headers("client"), Role::Admin),
+            Err(Error::Forbidden(_))
+        ));
+        assert!(matches!(
+            auth.authorize(&headers("other"), Role::Client),
+            Err(Error::Unauthorized(_))
+        ));
+        assert!(matches!(
+            auth.authorize(&HeaderMap::new(), Role::Client),
+            Err(Error::Unauthorized(_))
+        ));
+    }
+}
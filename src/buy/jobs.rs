@@ -0,0 +1,522 @@
+use super::{
+    decode_transaction, lines, parse_pubkey, record, reject_encoded, settle, submit, transfers,
+    transient, BuyState, Item,
+};
+use crate::{
+    auth::Client,
+    error::Error,
+    purchases::{self, Order, PurchaseStatus},
+    retry,
+    session::Buyer,
+    store::JobStore,
+};
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use solana_sdk::transaction::Transaction;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// Longest a worker sleeps before looking for due jobs again.
+const POLL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting for a worker, retries included.
+    Queued,
+    Running,
+    /// The transaction was submitted, its order tells whether it is confirmed.
+    Completed,
+    /// Rejected, or given up on after the maximum number of attempts, its
+    /// order then left pending for the watcher if the transaction may be sent.
+    Failed,
+}
+
+impl JobStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "queued" => Some(Self::Queued),
+            "running" => Some(Self::Running),
+            "completed" => Some(Self::Completed),
+            "failed" => Some(Self::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A `/buy` request, processed in the background by the worker pool.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub created_at: i64,
+    pub buyer: String,
+    /// Of the transaction, known before it is submitted.
+    pub signature: String,
+    pub service: String,
+    /// Base64 encoded, bincode serialized transaction signed by the buyer.
+    #[serde(skip_serializing)]
+    pub transaction: String,
+    pub status: JobStatus,
+    pub attempts: u32,
+    /// Unix timestamp, in seconds, of the next attempt while queued.
+    pub next_attempt_at: i64,
+    /// Why the last attempt failed.
+    pub error: Option<String>,
+    /// Recorded once the transaction is checked, and kept across retries.
+    pub order_id: Option<u64>,
+}
+
+/// How the worker pool processes jobs.
+#[derive(Debug, Clone, Copy)]
+pub struct Workers {
+    pub count: usize,
+    /// Attempts before a job failing with rpc errors is failed.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled after every failed one.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for Workers {
+    fn default() -> Self {
+        Self {
+            count: 4,
+            max_attempts: 5,
+            backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The durable jobs, and a signal waking an idle worker when one is queued.
+#[derive(Clone)]
+pub struct Queue {
+    pub jobs: Arc<dyn JobStore>,
+    wake: Arc<Notify>,
+}
+
+impl Queue {
+    pub fn new(jobs: Arc<dyn JobStore>) -> Self {
+        Self {
+            jobs,
+            wake: Arc::default(),
+        }
+    }
+
+    fn push(&self, job: &Job) -> Result<u64, Error> {
+        let id = self.jobs.insert_job(job)?;
+        self.wake.notify_one();
+        Ok(id)
+    }
+}
+
+/// A job and, once recorded, its order.
+#[derive(Debug, Serialize)]
+pub struct Purchase {
+    #[serde(flatten)]
+    job: Job,
+    order: Option<Order>,
+}
+
+/// Queues a purchase after checking the transaction is signed by the buyer,
+/// the signed in one if any, the rest is checked by the worker processing it.
+pub fn enqueue(
+    state: &BuyState,
+    buyer: Buyer,
+    transaction: &str,
+    service: &str,
+) -> Result<Purchase, Error> {
+    let decoded = decode_transaction(transaction)?;
+    decoded
+        .verify()
+        .map_err(|e| Error::Validation(format!("invalid transaction signature: {e}")))?;
+    let buyer = buyer.resolve(decoded.message.account_keys.first())?;
+    let now = purchases::now();
+    let mut job = Job {
+        id: 0,
+        created_at: now,
+        buyer: buyer.to_string(),
+        signature: decoded.signatures[0].to_string(),
+        service: service.into(),
+        transaction: transaction.into(),
+        status: JobStatus::Queued,
+        attempts: 0,
+        next_attempt_at: now,
+        error: None,
+        order_id: None,
+    };
+    job.id = state.queue.push(&job)?;
+    Ok(Purchase { job, order: None })
+}
+
+/// Retrieves a purchase, of the signed in buyer if any, with its order once
+/// recorded.
+#[tracing::instrument(skip(state))]
+pub async fn fetch(
+    _: Client,
+    buyer: Buyer,
+    State(state): State<BuyState>,
+    Path(id): Path<u64>,
+) -> Result<Json<Purchase>, Error> {
+    info!("retrieving purchase");
+    let job = state
+        .queue
+        .jobs
+        .get_job(id)?
+        .filter(|job| buyer.owns(&job.buyer))
+        .ok_or_else(|| Error::NotFound(format!("purchase {id} not found")))?;
+    let order = match job.order_id {
+        Some(order_id) => state.purchases.get_order(order_id)?,
+        None => None,
+    };
+    Ok(Json(Purchase { job, order }))
+}
+
+/// Starts the worker pool, after queueing again the jobs a previous process
+/// was running when it stopped.
+pub async fn run(state: BuyState, workers: Workers) {
+    match state.queue.jobs.requeue_running_jobs() {
+        Ok(0) => {}
+        Ok(count) => info!("resuming {count} purchase jobs"),
+        Err(e) => warn!("failed to resume purchase jobs: {e}"),
+    }
+    for _ in 0..workers.count {
+        tokio::spawn(work(state.clone(), workers));
+    }
+}
+
+async fn work(state: BuyState, workers: Workers) {
+    loop {
+        match state.queue.jobs.claim_job(purchases::now()) {
+            Ok(Some(job)) => {
+                let id = job.id;
+                if let Err(e) = attempt(&state, job, workers).await {
+                    warn!("failed to record purchase job {id}: {e}");
+                }
+            }
+            Ok(None) => {
+                let _ = tokio::time::timeout(POLL, state.queue.wake.notified()).await;
+            }
+            Err(e) => {
+                warn!("failed to read purchase jobs: {e}");
+                tokio::time::sleep(POLL).await;
+            }
+        }
+    }
+}
+
+async fn attempt(state: &BuyState, mut job: Job, workers: Workers) -> anyhow::Result<()> {
+    job.attempts += 1;
+    match process(state, &mut job).await {
+        Ok(()) => {
+            job.status = JobStatus::Completed;
+            job.error = None;
+        }
+        Err(e) if transient(&e) && job.attempts < workers.max_attempts => {
+            warn!("purchase job {} failed, retrying: {e}", job.id);
+            job.status = JobStatus::Queued;
+            job.error = Some(e.to_string());
+            job.next_attempt_at = purchases::now()
+                + retry::backoff(workers.backoff, workers.max_backoff, job.attempts).as_secs()
+                    as i64;
+        }
+        Err(e) => {
+            job.status = JobStatus::Failed;
+            job.error = Some(e.to_string());
+            if let Some(order_id) = job.order_id.filter(|_| transient(&e)) {
+                // the transaction may have been sent and land, the watcher
+                // settles its order or expires it
+                warn!(
+                    "gave up on purchase job {}, order {order_id} stays pending: {e}",
+                    job.id
+                );
+            } else if let Some(order_id) = job.order_id {
+                // the watcher may have confirmed the order meanwhile
+                if !settle(state, order_id, Err(&e))?
+                    && state
+                        .purchases
+                        .get_order(order_id)?
+                        .is_some_and(|order| order.status == PurchaseStatus::Confirmed)
+                {
+                    job.status = JobStatus::Completed;
+                    job.error = None;
+                }
+            } else {
+                // rejected before its order was recorded, nothing follows
+                let buyer = parse_pubkey("buyer", &job.buyer).ok();
+                let item = Item {
+                    vendor: String::new(),
+                    service: job.service.clone(),
+                    quantity: 1,
+                };
+                reject_encoded(state, buyer.as_ref(), &job.transaction, &[item], &e).await;
+                state.progress.stop(&job.signature);
+            }
+        }
+    }
+    state.queue.jobs.update_job(&job)?;
+    Ok(())
+}
+
+/// Checks the purchase, records its order on the first attempt which gets
+/// that far, then submits the transaction.
+async fn process(state: &BuyState, job: &mut Job) -> Result<(), Error> {
+    // the watcher may have settled the order since the last attempt
+    if let Some(order) = match job.order_id {
+        Some(order_id) => state.purchases.get_order(order_id)?,
+        None => None,
+    } {
+        match order.status {
+            PurchaseStatus::Pending => {}
+            PurchaseStatus::Confirmed => return Ok(()),
+            PurchaseStatus::Failed => {
+                return Err(Error::Validation(
+                    order.error.unwrap_or_else(|| "order failed".into()),
+                ))
+            }
+        }
+    }
+    let buyer = parse_pubkey("buyer", &job.buyer)?;
+    let transaction = decode_transaction(&job.transaction)?;
+    let payments = transfers(state, &transaction).await?;
+    let (order_id, sent) = match job.order_id {
+        // checked before it was recorded, and an earlier attempt may have
+        // sent the transaction before failing
+        Some(order_id) => (order_id, already_sent(state, &transaction).await?),
+        None => {
+            let [payment] = payments[..] else {
+                return Err(Error::Validation(
+                    "transaction must contain a single transfer".into(),
+                ));
+            };
+            let item = Item {
+                vendor: payment.vendor.to_string(),
+                service: job.service.clone(),
+                quantity: 1,
+            };
+            let lines = lines(state, &buyer, &payments, &[item])?;
+            let order_id = record(state, &buyer, &job.signature, &lines)?;
+            job.order_id = Some(order_id);
+            state.queue.jobs.update_job(job)?;
+            (order_id, false)
+        }
+    };
+    let result = submit(
+        state,
+        order_id,
+        &transaction,
+        &payments,
+        job.signature.clone(),
+        !sent,
+    )
+    .await;
+    let (_, settlement) = result?;
+    settle(state, order_id, Ok(settlement.status))?;
+    Ok(())
+}
+
+async fn already_sent(state: &BuyState, transaction: &Transaction) -> Result<bool, Error> {
+    let status = state
+        .client
+        .get_signature_statuses(&transaction.signatures[..1])
+        .await?
+        .value
+        .pop()
+        .flatten();
+    Ok(status.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::tests::{encode, signed_transfer, state},
+        *,
+    };
+    use crate::{
+        purchases::PurchaseFilter,
+        vendors::{Service, Vendor, VendorStatus},
+    };
+    use axum::{extract::State, routing::post, Router, Server};
+    use serde_json::{json, Value};
+    use solana_client::nonblocking::rpc_client::RpcClient;
+    use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const WORKERS: Workers = Workers {
+        count: 1,
+        max_attempts: 2,
+        backoff: Duration::from_secs(2),
+        max_backoff: Duration::from_secs(60),
+    };
+
+    /// Whitelists a vendor selling coffee for 5000 lamports.
+    fn coffee(state: &BuyState) -> Pubkey {
+        let wallet = Pubkey::new_unique();
+        let vendor = Vendor {
+            wallet_id: wallet.to_string(),
+            name: "Café".into(),
+            address: String::new(),
+            services: vec![Service {
+                id: "coffee".into(),
+                name: "Coffee".into(),
+                description: String::new(),
+                price: 5000,
+                mint: None,
+                active: true,
+            }],
+            accepted_mints: Vec::new(),
+            status: VendorStatus::Approved,
+        };
+        state.vendors.insert(&vendor).unwrap();
+        wallet
+    }
+
+    /// Queues a purchase of coffee from `vendor` and claims it.
+    fn claim(state: &BuyState, vendor: &Pubkey) -> Job {
+        let transaction = signed_transfer(&Keypair::new(), vendor, 5000);
+        let encoded = encode(&transaction);
+        enqueue(state, Buyer::Token, &encoded, "coffee").unwrap();
+        state
+            .queue
+            .jobs
+            .claim_job(purchases::now())
+            .unwrap()
+            .unwrap()
+    }
+
+    fn order(state: &BuyState, job: &Job) -> Order {
+        let order_id = job.order_id.expect("order is recorded");
+        state.purchases.get_order(order_id).unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn fails_rejected_jobs_and_retries_rpc_errors() {
+        let state = state();
+
+        let job = claim(&state, &Pubkey::new_unique());
+        attempt(&state, job.clone(), WORKERS).await.unwrap();
+        let rejected = state.queue.jobs.get_job(job.id).unwrap().unwrap();
+        assert_eq!((rejected.status, rejected.attempts), (JobStatus::Failed, 1));
+        assert!(rejected.error.unwrap().contains("not whitelisted"));
+        let purchases = state.purchases.list(&PurchaseFilter::default()).unwrap();
+        assert_eq!(purchases.len(), 1);
+        assert_eq!(purchases[0].status, PurchaseStatus::Failed);
+        assert_eq!(purchases[0].signature, job.signature);
+
+        // the rpc node is never reached: the order is recorded, then the
+        // transaction can not be sent
+        let job = claim(&state, &coffee(&state));
+        let before = purchases::now();
+        attempt(&state, job.clone(), WORKERS).await.unwrap();
+        let retried = state.queue.jobs.get_job(job.id).unwrap().unwrap();
+        assert_eq!((retried.status, retried.attempts), (JobStatus::Queued, 1));
+        assert!(retried.error.is_some());
+        assert!((before + 2..=purchases::now() + 2).contains(&retried.next_attempt_at));
+        assert_eq!(order(&state, &retried).status, PurchaseStatus::Pending);
+
+        // the last attempt gives up, leaving the order to the watcher since
+        // the transaction may have been sent
+        attempt(&state, retried, WORKERS).await.unwrap();
+        let failed = state.queue.jobs.get_job(job.id).unwrap().unwrap();
+        assert_eq!((failed.status, failed.attempts), (JobStatus::Failed, 2));
+        assert_eq!(order(&state, &failed).status, PurchaseStatus::Pending);
+    }
+
+    /// An rpc node failing `sendTransaction` after accepting it, the
+    /// transaction then being finalized, and counting the sends.
+    async fn rpc(sent: Arc<AtomicUsize>) -> RpcClient {
+        async fn handle(
+            State(sent): State<Arc<AtomicUsize>>,
+            Json(request): Json<Value>,
+        ) -> Json<Value> {
+            let result = match request["method"].as_str() {
+                Some("getVersion") => json!({ "solana-core": "1.15.2" }),
+                Some("sendTransaction") => {
+                    sent.fetch_add(1, Ordering::SeqCst);
+                    return Json(json!({
+                        "jsonrpc": "2.0",
+                        "id": request["id"],
+                        "error": { "code": -32603, "message": "internal error" },
+                    }));
+                }
+                Some("getSignatureStatuses") => {
+                    let status = (sent.load(Ordering::SeqCst) > 0).then(|| {
+                        json!({
+                            "slot": 7,
+                            "confirmations": null,
+                            "err": null,
+                            "status": { "Ok": null },
+                            "confirmationStatus": "finalized",
+                        })
+                    });
+                    json!({ "context": { "slot": 7 }, "value": [status] })
+                }
+                Some("getTransaction") => Value::Null,
+                method => panic!("unexpected rpc method {method:?}"),
+            };
+            Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+        }
+        let router = Router::new().route("/", post(handle)).with_state(sent);
+        let server =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(router.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        RpcClient::new(url)
+    }
+
+    #[tokio::test]
+    async fn resumes_running_jobs_without_sending_them_again() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let state = BuyState {
+            client: rpc(sent.clone()).await.into(),
+            ..state()
+        };
+        let workers = Workers {
+            backoff: Duration::ZERO,
+            ..WORKERS
+        };
+
+        let job = claim(&state, &coffee(&state));
+        attempt(&state, job.clone(), workers).await.unwrap();
+        let queued = state.queue.jobs.get_job(job.id).unwrap().unwrap();
+        assert_eq!(queued.status, JobStatus::Queued);
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        // the process stops while running it
+        let running = state.queue.jobs.claim_job(purchases::now()).unwrap();
+        assert_eq!(running.unwrap().status, JobStatus::Running);
+        run(state.clone(), workers).await;
+        let completed = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let job = state.queue.jobs.get_job(job.id).unwrap().unwrap();
+                if job.status != JobStatus::Queued && job.status != JobStatus::Running {
+                    return job;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            (completed.status, completed.attempts),
+            (JobStatus::Completed, 2)
+        );
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+        let order = order(&state, &completed);
+        assert_eq!(order.status, PurchaseStatus::Confirmed);
+        assert_eq!(order.receipt.slot, Some(7));
+    }
+}
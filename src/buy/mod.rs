@@ -0,0 +1,914 @@
+use crate::{
+    auth::Client,
+    error::Error,
+    idempotency::{self, SharedIdempotency},
+    payment::{self, Payment},
+    purchases::{Order, Purchase, PurchaseStatus, Receipt, SharedPurchases},
+    session::Buyer,
+    vendors::{SharedVendors, Vendor, VendorStatus},
+    webhooks::{self, SharedWebhooks},
+};
+use anyhow::Context;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcTransactionConfig};
+use solana_sdk::{
+    commitment_config::CommitmentConfig, message::Message, pubkey::Pubkey, signature::Signature,
+    transaction::Transaction,
+};
+use solana_transaction_status::{TransactionStatus, UiTransactionEncoding};
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+pub mod jobs;
+mod order;
+mod progress;
+mod solana_pay;
+pub mod watcher;
+
+use jobs::Queue;
+pub use progress::Tracker;
+use progress::{Progress, Stage};
+
+#[derive(Clone)]
+pub struct BuyState {
+    pub vendors: SharedVendors,
+    pub purchases: SharedPurchases,
+    pub idempotency: SharedIdempotency,
+    pub webhooks: SharedWebhooks,
+    pub progress: Arc<Tracker>,
+    pub queue: Queue,
+    pub client: Arc<RpcClient>,
+    pub confirmation: Confirmation,
+}
+
+/// How `buy` waits for a submitted transaction to reach the client's commitment level.
+#[derive(Debug, Clone, Copy)]
+pub struct Confirmation {
+    /// Delay before the first status check, doubled after every unsuccessful one.
+    pub poll_interval: Duration,
+    pub max_poll_interval: Duration,
+    /// Maximum time to wait before answering with a pending status.
+    pub timeout: Duration,
+}
+
+impl Default for Confirmation {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_millis(500),
+            max_poll_interval: Duration::from_secs(4),
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+pub fn router(state: BuyState) -> Router {
+    Router::new()
+        .route("/buy", post(buy))
+        .route("/buy/prepare", post(prepare))
+        .route("/purchases/:id", get(jobs::fetch))
+        .route("/orders", post(order::create))
+        .route("/orders/prepare", post(order::prepare))
+        .route("/orders/:id", get(order::fetch))
+        .route("/transactions/:signature/events", get(progress::stream))
+        .route("/pay", post(solana_pay::create))
+        .route("/pay/:reference", get(solana_pay::status))
+        .route("/pay/:reference/qr", get(solana_pay::qr))
+        .with_state(state)
+}
+
+/// A service of a whitelisted vendor, bought `quantity` times.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Item {
+    vendor: String,
+    service: String,
+    #[serde(default = "one")]
+    quantity: u32,
+}
+
+fn one() -> u32 {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+struct PrepareParams {
+    vendor: String,
+    /// Id of the purchased service, which sets the amount and the mint.
+    service: String,
+    /// Checked against the decimals of the service's mint if given.
+    decimals: Option<u8>,
+    /// Defaults to the signed in buyer, whom it must be. Required when
+    /// authenticated with an api token instead.
+    buyer: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct PreparedTransaction {
+    /// Base64 encoded, bincode serialized transaction the buyer has to sign.
+    transaction: String,
+    blockhash: String,
+}
+
+/// Builds an unsigned transfer paying the price of a service to a
+/// whitelisted vendor, in sol or in the spl token the service is priced in.
+#[tracing::instrument(skip(state))]
+async fn prepare(
+    _: Client,
+    buyer: Buyer,
+    State(state): State<BuyState>,
+    Json(params): Json<PrepareParams>,
+) -> Result<Json<PreparedTransaction>, Error> {
+    let claimed = params
+        .buyer
+        .as_deref()
+        .map(|claimed| parse_pubkey("buyer", claimed))
+        .transpose()?;
+    let buyer = buyer.resolve(claimed.as_ref())?;
+    let item = Item {
+        vendor: params.vendor,
+        service: params.service,
+        quantity: 1,
+    };
+    build(&state, buyer, &[item], params.decimals)
+        .await
+        .map(Json)
+}
+
+/// Builds an unsigned transaction with one transfer per item, in the order
+/// of the items.
+async fn build(
+    state: &BuyState,
+    buyer: Pubkey,
+    items: &[Item],
+    decimals: Option<u8>,
+) -> Result<PreparedTransaction, Error> {
+    let mut instructions = Vec::new();
+    for item in items {
+        let payment = price(&state.vendors, buyer, item)?;
+        instructions.extend(payment::instructions(&state.client, &payment, decimals).await?);
+    }
+    let blockhash = state.client.get_latest_blockhash().await?;
+    let message = Message::new_with_blockhash(&instructions, Some(&buyer), &blockhash);
+    let transaction = Transaction::new_unsigned(message);
+    Ok(PreparedTransaction {
+        transaction: base64::encode(
+            bincode::serialize(&transaction).context("failed to serialize transaction")?,
+        ),
+        blockhash: blockhash.to_string(),
+    })
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct BuyParams {
+    /// Base64 encoded, bincode serialized transaction signed by the buyer.
+    transaction: String,
+    /// Id of the purchased service, the transaction must pay its price.
+    service: String,
+}
+
+/// Where a submitted transaction stands, its receipt is empty while pending.
+#[derive(Debug, Serialize)]
+struct Settlement {
+    signature: String,
+    status: PurchaseStatus,
+    #[serde(flatten)]
+    receipt: Receipt,
+}
+
+#[derive(Debug, Serialize)]
+struct OrderReceipt {
+    order_id: u64,
+    #[serde(flatten)]
+    settlement: Settlement,
+    buyer: String,
+    items: Vec<Line>,
+}
+
+/// An item with the total the transaction pays for it.
+#[derive(Debug, Serialize)]
+struct Line {
+    #[serde(flatten)]
+    item: Item,
+    /// In lamports, or in base units of `mint`.
+    amount: u64,
+    /// `amount` of native sol lines, under its name from before token
+    /// payments for older clients, absent otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lamports: Option<u64>,
+    /// Absent for native sol.
+    mint: Option<String>,
+}
+
+/// Queues a buyer signed transaction paying a whitelisted vendor the price
+/// of the purchased service, and answers `202 Accepted` with the purchase
+/// to poll at `GET /purchases/:id`. A worker checks the payment, records it
+/// as an order of a single item, and submits the transaction. Rejected
+/// requests are recorded as failed orders, here or by the worker.
+///
+/// When an `Idempotency-Key` header is sent, the outcome of the first request
+/// is stored and replayed for every retry with the same key and body.
+#[tracing::instrument(skip(state, headers))]
+async fn buy(
+    _: Client,
+    buyer: Buyer,
+    State(state): State<BuyState>,
+    headers: HeaderMap,
+    Json(params): Json<BuyParams>,
+) -> Result<Response, Error> {
+    let body = serde_json::to_vec(&params).context("failed to serialize request")?;
+    idempotency::run(state.idempotency.clone(), &headers, &body, async move {
+        let purchase = jobs::enqueue(&state, buyer, &params.transaction, &params.service);
+        if let Err(e) = &purchase {
+            let item = Item {
+                vendor: String::new(),
+                service: params.service.clone(),
+                quantity: 1,
+            };
+            reject_encoded(&state, None, &params.transaction, &[item], e).await;
+        }
+        Ok((StatusCode::ACCEPTED, purchase?))
+    })
+    .await
+}
+
+/// Verifies the signatures of a transaction and extracts its transfers,
+/// which must all be paid by the fee payer.
+async fn transfers(state: &BuyState, transaction: &Transaction) -> Result<Vec<Payment>, Error> {
+    transaction
+        .verify()
+        .map_err(|e| Error::Validation(format!("invalid transaction signature: {e}")))?;
+    let payments = payment::payments(&state.client, &transaction.message).await?;
+    let fee_payer = transaction.message.account_keys.first();
+    if payments
+        .iter()
+        .any(|payment| Some(&payment.buyer) != fee_payer)
+    {
+        return Err(Error::Validation(
+            "every transfer must be paid by the fee payer".into(),
+        ));
+    }
+    Ok(payments)
+}
+
+/// Checks the `i`th transfer pays for the `i`th item, then records the order
+/// and submits its transaction.
+async fn checkout(
+    state: &BuyState,
+    signed_in: &Pubkey,
+    transaction: &Transaction,
+    payments: &[Payment],
+    items: &[Item],
+) -> Result<(StatusCode, OrderReceipt), Error> {
+    let signature = transaction.signatures[0].to_string();
+    let checked = lines(state, signed_in, payments, items)
+        .and_then(|lines| Ok((record(state, signed_in, &signature, &lines)?, lines)));
+    let (order_id, lines) = match checked {
+        Ok(checked) => checked,
+        Err(e) => {
+            reject(
+                state,
+                Some(signed_in),
+                Some(transaction),
+                payments,
+                items,
+                &e,
+            );
+            return Err(e);
+        }
+    };
+    let result = submit(state, order_id, transaction, payments, signature, true).await;
+    match &result {
+        // the node may have broadcast it before failing, the watcher settles
+        // the order or expires it
+        Err(e) if transient(e) => warn!("order {order_id} stays pending: {e}"),
+        result => {
+            settle(
+                state,
+                order_id,
+                result.as_ref().map(|(_, settlement)| settlement.status),
+            )?;
+        }
+    }
+    let (status, settlement) = result?;
+    Ok((
+        status,
+        OrderReceipt {
+            order_id,
+            settlement,
+            buyer: signed_in.to_string(),
+            items: lines,
+        },
+    ))
+}
+
+/// Checks the `i`th transfer pays for the `i`th item.
+fn lines(
+    state: &BuyState,
+    signed_in: &Pubkey,
+    payments: &[Payment],
+    items: &[Item],
+) -> Result<Vec<Line>, Error> {
+    if payments.len() != items.len() {
+        return Err(Error::Validation(format!(
+            "transaction contains {} transfers for {} items",
+            payments.len(),
+            items.len()
+        )));
+    }
+    let mut lines = Vec::with_capacity(items.len());
+    for (item, paid) in items.iter().zip(payments) {
+        check_buyer(signed_in, &paid.buyer)?;
+        check_payment(item, &price(&state.vendors, paid.buyer, item)?, paid)?;
+        lines.push(Line {
+            item: item.clone(),
+            amount: paid.amount,
+            lamports: paid.mint.is_none().then_some(paid.amount),
+            mint: paid.mint.map(|mint| mint.to_string()),
+        });
+    }
+    Ok(lines)
+}
+
+/// Records a pending order of the checked lines, and returns its id. A
+/// transaction only pays a single order, unless the earlier ones failed.
+fn record(state: &BuyState, buyer: &Pubkey, signature: &str, lines: &[Line]) -> Result<u64, Error> {
+    let order_id = state
+        .purchases
+        .insert_order(&Order::pending(
+            buyer.to_string(),
+            signature.into(),
+            lines
+                .iter()
+                .map(|line| {
+                    Purchase::item(
+                        line.item.vendor.clone(),
+                        line.item.service.clone(),
+                        line.item.quantity,
+                        line.amount,
+                        line.mint.clone(),
+                    )
+                })
+                .collect(),
+        ))?
+        .ok_or_else(|| {
+            Error::Conflict(format!(
+                "transaction {signature} already pays another order"
+            ))
+        })?;
+    state
+        .progress
+        .publish(Progress::new(order_id, signature, Stage::Built));
+    Ok(order_id)
+}
+
+/// Records a purchase attempt rejected before its order was, as a failed
+/// order of the requested items completed with what the transaction tells of
+/// them. The buyer defaults to the fee payer. Only logs failures since the
+/// request is answered with `error` anyway.
+fn reject(
+    state: &BuyState,
+    buyer: Option<&Pubkey>,
+    transaction: Option<&Transaction>,
+    payments: &[Payment],
+    items: &[Item],
+    error: &Error,
+) {
+    let buyer = buyer
+        .or_else(|| transaction.and_then(|transaction| transaction.message.account_keys.first()))
+        .map(ToString::to_string)
+        .unwrap_or_default();
+    let signature = transaction
+        .and_then(|transaction| transaction.signatures.first())
+        .map(ToString::to_string)
+        .unwrap_or_default();
+    let lines = (0..items.len().max(payments.len()))
+        .map(|i| {
+            let (item, paid) = (items.get(i), payments.get(i));
+            Purchase::item(
+                paid.map(|paid| paid.vendor.to_string())
+                    .or_else(|| item.map(|item| item.vendor.clone()))
+                    .unwrap_or_default(),
+                item.map(|item| item.service.clone()).unwrap_or_default(),
+                item.map_or(1, |item| item.quantity),
+                paid.map_or(0, |paid| paid.amount),
+                paid.and_then(|paid| paid.mint).map(|mint| mint.to_string()),
+            )
+        })
+        .collect();
+    let order = Order::rejected(buyer, signature, lines, error.to_string());
+    if let Err(e) = state.purchases.insert_order(&order) {
+        warn!("failed to record a rejected purchase: {e}");
+    }
+}
+
+/// Records a purchase attempt rejected before its transaction was checked,
+/// with the transfers it contains if it decodes.
+async fn reject_encoded(
+    state: &BuyState,
+    buyer: Option<&Pubkey>,
+    transaction: &str,
+    items: &[Item],
+    error: &Error,
+) {
+    let transaction = decode_transaction(transaction).ok();
+    let payments = match &transaction {
+        Some(transaction) => payment::payments(&state.client, &transaction.message)
+            .await
+            .unwrap_or_default(),
+        None => Vec::new(),
+    };
+    reject(state, buyer, transaction.as_ref(), &payments, items, error);
+}
+
+/// Records the outcome of submitting an order's transaction, unless the
+/// order was already settled, by the watcher for instance. Returns whether
+/// the order was settled.
+fn settle(
+    state: &BuyState,
+    order_id: u64,
+    outcome: Result<PurchaseStatus, &Error>,
+) -> Result<bool, Error> {
+    let settled = match outcome {
+        Ok(PurchaseStatus::Pending) => false,
+        Ok(status) => state.purchases.set_order_status(order_id, status, None)?,
+        Err(e) => state.purchases.set_order_status(
+            order_id,
+            PurchaseStatus::Failed,
+            Some(&e.to_string()),
+        )?,
+    };
+    if settled {
+        notify(state, order_id);
+    }
+    Ok(settled)
+}
+
+/// Rpc failures which may not happen again, after which a transaction may
+/// have been sent.
+fn transient(error: &Error) -> bool {
+    matches!(error, Error::Rpc(_) | Error::Timeout(_))
+}
+
+/// Sends the transaction, unless `send` is false because it already was, and
+/// waits for it to be confirmed. Once sent, it only fails if the chain
+/// rejects the transaction: it may still land after an rpc failure, so its
+/// order is left pending for the watcher, as when confirmation times out.
+async fn submit(
+    state: &BuyState,
+    order_id: u64,
+    transaction: &Transaction,
+    payments: &[Payment],
+    signature: String,
+    send: bool,
+) -> Result<(StatusCode, Settlement), Error> {
+    let mut settlement = Settlement {
+        signature,
+        status: PurchaseStatus::Pending,
+        receipt: Receipt::default(),
+    };
+    let sig = if send {
+        // vendors may have been revoked since the order was checked
+        for payment in payments {
+            whitelisted(&state.vendors, &payment.vendor)?;
+        }
+        state.client.send_transaction(transaction).await?
+    } else {
+        transaction.signatures[0]
+    };
+    let publish = |stage, slot| {
+        state.progress.publish(Progress {
+            slot,
+            ..Progress::new(order_id, &settlement.signature, stage)
+        })
+    };
+    publish(Stage::Submitted, None);
+    let confirmed = match confirm(
+        &state.client,
+        &sig,
+        state.client.commitment(),
+        state.confirmation,
+        |status| publish(status.confirmation_status().into(), Some(status.slot)),
+    )
+    .await
+    {
+        Ok(confirmed) => confirmed,
+        Err(e) if transient(&e) => {
+            warn!("failed to confirm {}: {e}", settlement.signature);
+            None
+        }
+        // rejected by the chain
+        Err(e) => return Err(e),
+    };
+    let Some(status) = confirmed else {
+        state.progress.stop(&settlement.signature);
+        return Ok((StatusCode::ACCEPTED, settlement));
+    };
+    if !state.client.commitment().is_finalized() {
+        tokio::spawn(finalize(state.clone(), order_id, sig));
+    }
+    settlement.status = PurchaseStatus::Confirmed;
+    settlement.receipt = Receipt {
+        commitment: Some(status.confirmation_status()),
+        slot: Some(status.slot),
+        fee: fee(&state.client, &sig).await,
+    };
+    record_receipt(state, order_id, &settlement.receipt);
+    Ok((StatusCode::OK, settlement))
+}
+
+/// Keeps polling a confirmed transaction until it is finalized, for the
+/// clients streaming its progress.
+async fn finalize(state: BuyState, order_id: u64, sig: Signature) {
+    let signature = sig.to_string();
+    let result = confirm(
+        &state.client,
+        &sig,
+        CommitmentConfig::finalized(),
+        state.confirmation,
+        |status| {
+            state.progress.publish(Progress {
+                slot: Some(status.slot),
+                ..Progress::new(order_id, &signature, status.confirmation_status().into())
+            })
+        },
+    )
+    .await;
+    match result {
+        Ok(Some(status)) => {
+            let receipt = Receipt {
+                commitment: Some(status.confirmation_status()),
+                slot: Some(status.slot),
+                fee: None,
+            };
+            return record_receipt(&state, order_id, &receipt);
+        }
+        Ok(None) => {}
+        Err(e) => warn!("failed to follow {signature} until finalized: {e}"),
+    }
+    state.progress.stop(&signature);
+}
+
+/// Polls the signature status with exponential backoff, reporting every
+/// status seen, until it reaches `commitment`. Returns `None` if it did not
+/// once `confirmation.timeout` elapsed.
+async fn confirm(
+    client: &RpcClient,
+    sig: &Signature,
+    commitment: CommitmentConfig,
+    confirmation: Confirmation,
+    report: impl Fn(&TransactionStatus),
+) -> Result<Option<TransactionStatus>, Error> {
+    let deadline = Instant::now() + confirmation.timeout;
+    let mut interval = confirmation.poll_interval;
+    loop {
+        let status = client.get_signature_statuses(&[*sig]).await?.value.pop();
+        if let Some(status) = status.flatten() {
+            if let Some(err) = &status.err {
+                return Err(Error::Validation(format!("transaction failed: {err}")));
+            }
+            report(&status);
+            if status.satisfies_commitment(commitment) {
+                return Ok(Some(status));
+            }
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        tokio::time::sleep(interval.min(remaining)).await;
+        interval = (interval * 2).min(confirmation.max_poll_interval);
+    }
+}
+
+/// Looks up the fee paid by a confirmed transaction. Only logs failures since
+/// the purchase itself already succeeded.
+async fn fee(client: &RpcClient, sig: &Signature) -> Option<u64> {
+    let config = RpcTransactionConfig {
+        encoding: Some(UiTransactionEncoding::Base64),
+        commitment: Some(lookup_commitment(client)),
+        max_supported_transaction_version: Some(0),
+    };
+    match client.get_transaction_with_config(sig, config).await {
+        Ok(tx) => tx.transaction.meta.map(|meta| meta.fee),
+        Err(e) => {
+            warn!("failed to fetch fee of {sig}: {e}");
+            None
+        }
+    }
+}
+
+/// Records where the transaction paying an order landed. Only logs failures
+/// since the order itself is settled regardless.
+fn record_receipt(state: &BuyState, order_id: u64, receipt: &Receipt) {
+    if let Err(e) = state.purchases.set_order_receipt(order_id, receipt) {
+        warn!("failed to record the receipt of order {order_id}: {e}");
+    }
+}
+
+/// Tells progress streams and webhooks an order was just confirmed or
+/// failed. Only logs failures since the order itself is already recorded.
+fn notify(state: &BuyState, order_id: u64) {
+    let order = match state.purchases.get_order(order_id) {
+        Ok(Some(order)) => order,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("failed to read order {order_id}: {e}");
+            return;
+        }
+    };
+    let stage = match order.status {
+        PurchaseStatus::Confirmed => Stage::Confirmed,
+        PurchaseStatus::Failed => Stage::Failed,
+        PurchaseStatus::Pending => return,
+    };
+    state.progress.publish(Progress {
+        error: order.error.clone(),
+        ..Progress::new(order_id, &order.signature, stage)
+    });
+    if let Err(e) = webhooks::enqueue(&state.webhooks, &order) {
+        warn!("failed to queue webhook events of order {order_id}: {e}");
+    }
+}
+
+/// Commitment to fetch transactions at, which can not be processed.
+fn lookup_commitment(client: &RpcClient) -> CommitmentConfig {
+    if client.commitment().is_finalized() {
+        CommitmentConfig::finalized()
+    } else {
+        CommitmentConfig::confirmed()
+    }
+}
+
+/// Returns the vendor if it is approved.
+fn whitelisted(vendors: &SharedVendors, wallet_id: &Pubkey) -> Result<Vendor, Error> {
+    let wallet_id = wallet_id.to_string();
+    vendors
+        .get(&wallet_id)?
+        .filter(|vendor| vendor.status == VendorStatus::Approved)
+        .ok_or(Error::NotWhitelisted(wallet_id))
+}
+
+/// Returns the payment due for an item, checking the vendor is whitelisted
+/// and sells the service.
+fn price(vendors: &SharedVendors, buyer: Pubkey, item: &Item) -> Result<Payment, Error> {
+    let vendor = parse_pubkey("vendor", &item.vendor)?;
+    let service = whitelisted(vendors, &vendor)?
+        .service(&item.service)?
+        .clone();
+    if item.quantity == 0 {
+        return Err(Error::Validation(format!(
+            "quantity of service {} must be at least 1",
+            service.id
+        )));
+    }
+    let amount = service
+        .price
+        .checked_mul(item.quantity.into())
+        .ok_or_else(|| {
+            Error::Validation(format!(
+                "{} x service {} overflows the price",
+                item.quantity, service.id
+            ))
+        })?;
+    Ok(Payment {
+        buyer,
+        vendor,
+        amount,
+        mint: service
+            .mint
+            .as_deref()
+            .map(|mint| parse_pubkey("mint", mint))
+            .transpose()?,
+    })
+}
+
+fn check_payment(item: &Item, due: &Payment, paid: &Payment) -> Result<(), Error> {
+    if paid.vendor != due.vendor {
+        return Err(Error::Validation(format!(
+            "service {} is sold by {}, the transaction pays {}",
+            item.service, due.vendor, paid.vendor
+        )));
+    }
+    if paid.amount != due.amount || paid.mint != due.mint {
+        let unit =
+            |mint: &Option<Pubkey>| mint.map_or_else(|| "lamports".into(), |mint| mint.to_string());
+        return Err(Error::Validation(format!(
+            "{} x service {} costs {} {}, the transaction pays {} {}",
+            item.quantity,
+            item.service,
+            due.amount,
+            unit(&due.mint),
+            paid.amount,
+            unit(&paid.mint)
+        )));
+    }
+    Ok(())
+}
+
+fn check_buyer(signed_in: &Pubkey, buyer: &Pubkey) -> Result<(), Error> {
+    if signed_in != buyer {
+        return Err(Error::Forbidden(format!(
+            "signed in as {signed_in}, can not purchase as {buyer}"
+        )));
+    }
+    Ok(())
+}
+
+fn parse_pubkey(field: &str, value: &str) -> Result<Pubkey, Error> {
+    Pubkey::from_str(value).map_err(|e| Error::Validation(format!("invalid {field} {value}: {e}")))
+}
+
+fn decode_transaction(encoded: &str) -> Result<Transaction, Error> {
+    let bytes = base64::decode(encoded)
+        .map_err(|e| Error::Validation(format!("transaction is not valid base64: {e}")))?;
+    let transaction: Transaction = bincode::deserialize(&bytes)
+        .map_err(|e| Error::Validation(format!("transaction could not be decoded: {e}")))?;
+    // `verify` only checks the signatures present, and the first one identifies it
+    let required = usize::from(transaction.message.header.num_required_signatures);
+    if required == 0 {
+        return Err(Error::Validation(
+            "transaction requires no signature".into(),
+        ));
+    }
+    if transaction.signatures.len() != required {
+        return Err(Error::Validation(format!(
+            "transaction has {} signatures, {required} are required",
+            transaction.signatures.len()
+        )));
+    }
+    Ok(transaction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::StoreKind, purchases::PurchaseFilter, store};
+    use solana_sdk::{signature::Keypair, signer::Signer, system_instruction};
+    use std::path::Path;
+
+    /// In memory stores, and an rpc node which is never reached.
+    pub fn state() -> BuyState {
+        let stores = store::open(StoreKind::Memory, Path::new("")).unwrap();
+        BuyState {
+            vendors: stores.vendors,
+            purchases: stores.purchases,
+            idempotency: stores.idempotency,
+            webhooks: stores.webhooks,
+            progress: Arc::default(),
+            queue: Queue::new(stores.jobs),
+            client: RpcClient::new("http://127.0.0.1:1".into()).into(),
+            confirmation: Confirmation::default(),
+        }
+    }
+
+    pub fn encode(transaction: &Transaction) -> String {
+        base64::encode(bincode::serialize(transaction).unwrap())
+    }
+
+    pub fn signed_transfer(buyer: &Keypair, vendor: &Pubkey, lamports: u64) -> Transaction {
+        let transfer = system_instruction::transfer(&buyer.pubkey(), vendor, lamports);
+        let mut transaction = Transaction::new_with_payer(&[transfer], Some(&buyer.pubkey()));
+        transaction.sign(&[buyer], Default::default());
+        transaction
+    }
+
+    #[tokio::test]
+    async fn records_rejected_attempts() {
+        let state = state();
+        let (buyer, vendor) = (Keypair::new(), Pubkey::new_unique());
+        let transaction = signed_transfer(&buyer, &vendor, 5000);
+        let payments = transfers(&state, &transaction).await.unwrap();
+        let item = Item {
+            vendor: vendor.to_string(),
+            service: "coffee".into(),
+            quantity: 1,
+        };
+        let result = checkout(&state, &buyer.pubkey(), &transaction, &payments, &[item]).await;
+        assert!(matches!(result, Err(Error::NotWhitelisted(_))));
+
+        let item = Item {
+            vendor: String::new(),
+            service: "tea".into(),
+            quantity: 1,
+        };
+        let error = Error::Validation("transaction could not be decoded".into());
+        reject_encoded(&state, None, "not a transaction", &[item], &error).await;
+
+        let purchases = state.purchases.list(&PurchaseFilter::default()).unwrap();
+        let [undecoded, unlisted] = &purchases[..] else {
+            panic!("expected two purchases, got {purchases:?}");
+        };
+        assert_eq!(unlisted.status, PurchaseStatus::Failed);
+        assert_eq!(unlisted.buyer, buyer.pubkey().to_string());
+        assert_eq!(unlisted.vendor, vendor.to_string());
+        assert_eq!(unlisted.amount, 5000);
+        assert_eq!(unlisted.signature, transaction.signatures[0].to_string());
+        assert!(unlisted
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("not whitelisted"));
+        assert_eq!(undecoded.status, PurchaseStatus::Failed);
+        assert_eq!((undecoded.buyer.as_str(), undecoded.amount), ("", 0));
+        assert_eq!(undecoded.service.as_deref(), Some("tea"));
+    }
+
+    #[test]
+    fn refuses_transactions_missing_signatures() {
+        let mut transaction = signed_transfer(&Keypair::new(), &Pubkey::new_unique(), 5);
+        assert!(decode_transaction(&encode(&transaction)).is_ok());
+
+        // verifies, as no signature is checked
+        transaction.signatures.clear();
+        assert!(transaction.verify().is_ok());
+        assert!(matches!(
+            decode_transaction(&encode(&transaction)),
+            Err(Error::Validation(e)) if e.contains("1 are required")
+        ));
+        transaction.message.header.num_required_signatures = 0;
+        assert!(decode_transaction(&encode(&transaction)).is_err());
+    }
+
+    #[test]
+    fn prices_items_and_checks_what_transfers_pay() {
+        let state = state();
+        let (buyer, wallet, mint) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let vendor = serde_json::from_value(serde_json::json!({
+            "wallet_id": wallet.to_string(),
+            "name": "Café",
+            "services": [
+                { "id": "coffee", "name": "Coffee", "price": 5000 },
+                { "id": "tea", "name": "Tea", "price": u64::MAX, "mint": mint.to_string() },
+            ],
+            "accepted_mints": [mint.to_string()],
+        }))
+        .unwrap();
+        state.vendors.insert(&vendor).unwrap();
+        let item = |service: &str, quantity| Item {
+            vendor: wallet.to_string(),
+            service: service.into(),
+            quantity,
+        };
+
+        let coffee = item("coffee", 3);
+        let due = price(&state.vendors, buyer, &coffee).unwrap();
+        assert_eq!(
+            due,
+            Payment {
+                buyer,
+                vendor: wallet,
+                amount: 15000,
+                mint: None
+            }
+        );
+        let tea = price(&state.vendors, buyer, &item("tea", 1)).unwrap();
+        assert_eq!((tea.amount, tea.mint), (u64::MAX, Some(mint)));
+        for invalid in [item("coffee", 0), item("tea", 2), item("cake", 1)] {
+            assert!(price(&state.vendors, buyer, &invalid).is_err());
+        }
+        let unlisted = Item {
+            vendor: Pubkey::new_unique().to_string(),
+            ..item("coffee", 1)
+        };
+        assert!(matches!(
+            price(&state.vendors, buyer, &unlisted),
+            Err(Error::NotWhitelisted(_))
+        ));
+
+        assert!(check_payment(&coffee, &due, &due).is_ok());
+        let wrong = [
+            Payment {
+                amount: 5000,
+                ..due
+            },
+            Payment {
+                mint: Some(mint),
+                ..due
+            },
+            Payment {
+                vendor: Pubkey::new_unique(),
+                ..due
+            },
+        ];
+        for paid in wrong {
+            assert!(matches!(
+                check_payment(&coffee, &due, &paid),
+                Err(Error::Validation(_))
+            ));
+        }
+    }
+}
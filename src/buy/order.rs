@@ -0,0 +1,197 @@
+use super::{
+    build, checkout, decode_transaction, parse_pubkey, reject_encoded, transfers, BuyState, Item,
+    PreparedTransaction,
+};
+use crate::{auth::Client, error::Error, idempotency, purchases::Order, session::Buyer};
+use anyhow::Context;
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    response::Response,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// Body of `POST /orders/prepare`.
+#[derive(Debug, Deserialize)]
+pub struct PrepareParams {
+    items: Vec<Item>,
+    /// Defaults to the signed in buyer, whom it must be. Required when
+    /// authenticated with an api token instead.
+    buyer: Option<String>,
+}
+
+/// Builds a single unsigned transaction paying for every item, one transfer
+/// per item, to be signed by the buyer and submitted to `POST /orders`.
+#[tracing::instrument(skip(state))]
+pub async fn prepare(
+    _: Client,
+    buyer: Buyer,
+    State(state): State<BuyState>,
+    Json(params): Json<PrepareParams>,
+) -> Result<Json<PreparedTransaction>, Error> {
+    info!("preparing order");
+    check_items(&params.items)?;
+    let claimed = params
+        .buyer
+        .as_deref()
+        .map(|claimed| parse_pubkey("buyer", claimed))
+        .transpose()?;
+    let buyer = buyer.resolve(claimed.as_ref())?;
+    build(&state, buyer, &params.items, None).await.map(Json)
+}
+
+/// Body of `POST /orders`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OrderParams {
+    /// Base64 encoded, bincode serialized transaction signed by the buyer,
+    /// its `i`th transfer paying for the `i`th item.
+    transaction: String,
+    items: Vec<Item>,
+}
+
+/// Submits a buyer signed transaction paying for several items, recording
+/// them as one order. Answers and replays like `POST /buy`.
+#[tracing::instrument(skip(state, headers))]
+pub async fn create(
+    _: Client,
+    buyer: Buyer,
+    State(state): State<BuyState>,
+    headers: HeaderMap,
+    Json(params): Json<OrderParams>,
+) -> Result<Response, Error> {
+    info!("submitting order");
+    let body = serde_json::to_vec(&params).context("failed to serialize request")?;
+    idempotency::run(state.idempotency.clone(), &headers, &body, async move {
+        let checked = async {
+            check_items(&params.items)?;
+            let transaction = decode_transaction(&params.transaction)?;
+            let buyer = buyer.resolve(transaction.message.account_keys.first())?;
+            let payments = transfers(&state, &transaction).await?;
+            Ok((transaction, buyer, payments))
+        }
+        .await;
+        let (transaction, buyer, payments) = match checked {
+            Ok(checked) => checked,
+            Err(e) => {
+                reject_encoded(&state, None, &params.transaction, &params.items, &e).await;
+                return Err(e);
+            }
+        };
+        checkout(&state, &buyer, &transaction, &payments, &params.items).await
+    })
+    .await
+}
+
+/// Looks up an order, of the signed in buyer if any, with its items.
+#[tracing::instrument(skip(state))]
+pub async fn fetch(
+    _: Client,
+    buyer: Buyer,
+    State(state): State<BuyState>,
+    Path(id): Path<u64>,
+) -> Result<Json<Order>, Error> {
+    info!("retrieving order");
+    state
+        .purchases
+        .get_order(id)?
+        .filter(|order| buyer.owns(&order.buyer))
+        .map(Json)
+        .ok_or_else(|| Error::NotFound(format!("order {id} not found")))
+}
+
+fn check_items(items: &[Item]) -> Result<(), Error> {
+    if items.is_empty() {
+        return Err(Error::Validation("an order needs at least one item".into()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{lines, tests::state},
+        *,
+    };
+    use crate::{error::Error, purchases::PurchaseStatus};
+    use solana_sdk::{
+        pubkey::Pubkey, signature::Keypair, signer::Signer, system_instruction,
+        transaction::Transaction,
+    };
+
+    #[tokio::test]
+    async fn checks_every_item_against_its_transfer() {
+        let state = state();
+        let (cafe, bakery) = (Pubkey::new_unique(), Pubkey::new_unique());
+        for (wallet, service, price) in [(cafe, "coffee", 5000), (bakery, "bread", 3000)] {
+            let vendor = serde_json::from_value(serde_json::json!({
+                "wallet_id": wallet.to_string(),
+                "name": "Shop",
+                "services": [{ "id": service, "name": service, "price": price }],
+            }))
+            .unwrap();
+            state.vendors.insert(&vendor).unwrap();
+        }
+        let buyer = Keypair::new();
+        let instructions = [
+            system_instruction::transfer(&buyer.pubkey(), &cafe, 10000),
+            system_instruction::transfer(&buyer.pubkey(), &bakery, 3000),
+        ];
+        let mut transaction = Transaction::new_with_payer(&instructions, Some(&buyer.pubkey()));
+        transaction.sign(&[&buyer], Default::default());
+        let payments = transfers(&state, &transaction).await.unwrap();
+        let item = |vendor: &Pubkey, service: &str, quantity| Item {
+            vendor: vendor.to_string(),
+            service: service.into(),
+            quantity,
+        };
+        let (coffees, bread) = (item(&cafe, "coffee", 2), item(&bakery, "bread", 1));
+
+        assert!(check_items(&[]).is_err());
+        let checked = lines(
+            &state,
+            &buyer.pubkey(),
+            &payments,
+            &[coffees.clone(), bread.clone()],
+        )
+        .unwrap();
+        let amounts: Vec<_> = checked.iter().map(|line| line.amount).collect();
+        assert_eq!(amounts, [10000, 3000]);
+        let mismatched = [
+            vec![bread.clone(), coffees.clone()],
+            vec![coffees.clone()],
+            vec![item(&cafe, "coffee", 1), bread.clone()],
+        ];
+        for items in mismatched {
+            let result = lines(&state, &buyer.pubkey(), &payments, &items);
+            assert!(matches!(result, Err(Error::Validation(_))), "{items:?}");
+        }
+        let other = Pubkey::new_unique();
+        let result = lines(&state, &other, &payments, &[coffees.clone(), bread]);
+        assert!(matches!(result, Err(Error::Forbidden(_))));
+
+        // the rpc node is never reached, the order stays pending as the
+        // transaction may have been sent
+        let result = checkout(
+            &state,
+            &buyer.pubkey(),
+            &transaction,
+            &payments,
+            &[coffees, item(&bakery, "bread", 1)],
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Rpc(_))));
+        let order = state.purchases.get_order(1).unwrap().unwrap();
+        assert_eq!(order.status, PurchaseStatus::Pending);
+        let items: Vec<_> = order
+            .items
+            .iter()
+            .map(|item| (item.vendor.clone(), item.quantity, item.amount))
+            .collect();
+        assert_eq!(
+            items,
+            [(cafe.to_string(), 2, 10000), (bakery.to_string(), 1, 3000)]
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use super::jobs::JobStatus;
+use super::BuyState;
+use crate::{
+    auth::Client,
+    error::Error,
+    purchases::{Order, PurchaseStatus},
+};
+use axum::{
+    extract::{Path, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::{stream, Stream};
+use serde::Serialize;
+use solana_sdk::{clock::Slot, signature::Signature};
+use solana_transaction_status::TransactionConfirmationStatus;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+use tracing::info;
+
+/// Time the progress of a transaction is kept after its last change.
+const RETENTION: Duration = Duration::from_secs(600);
+
+/// Stage of a submitted transaction, in the order they are reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    /// Checked and recorded, about to be sent.
+    Built,
+    Submitted,
+    Processed,
+    Confirmed,
+    Finalized,
+    Failed,
+}
+
+impl Stage {
+    fn is_final(self) -> bool {
+        matches!(self, Self::Finalized | Self::Failed)
+    }
+}
+
+impl From<TransactionConfirmationStatus> for Stage {
+    fn from(status: TransactionConfirmationStatus) -> Self {
+        match status {
+            TransactionConfirmationStatus::Processed => Self::Processed,
+            TransactionConfirmationStatus::Confirmed => Self::Confirmed,
+            TransactionConfirmationStatus::Finalized => Self::Finalized,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    pub order_id: u64,
+    pub signature: String,
+    pub stage: Stage,
+    /// Slot the transaction was processed in, once it was.
+    pub slot: Option<Slot>,
+    /// Why the transaction failed.
+    pub error: Option<String>,
+}
+
+impl Progress {
+    pub fn new(order_id: u64, signature: &str, stage: Stage) -> Self {
+        Self {
+            order_id,
+            signature: signature.into(),
+            stage,
+            slot: None,
+            error: None,
+        }
+    }
+
+    /// The progress of an order once settled.
+    fn settled(order: &Order) -> Option<Self> {
+        let stage = match order.status {
+            PurchaseStatus::Pending => return None,
+            PurchaseStatus::Confirmed => Stage::Confirmed,
+            PurchaseStatus::Failed => Stage::Failed,
+        };
+        Some(Self {
+            error: order.error.clone(),
+            ..Self::new(order.id, &order.signature, stage)
+        })
+    }
+}
+
+/// Latest progress of recent transactions, by signature, kept in memory for
+/// the clients streaming it.
+#[derive(Default)]
+pub struct Tracker {
+    channels: Mutex<HashMap<String, Channel>>,
+}
+
+struct Channel {
+    sender: watch::Sender<Option<Progress>>,
+    updated: Instant,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            sender: watch::channel(None).0,
+            updated: Instant::now(),
+        }
+    }
+}
+
+impl Tracker {
+    /// Records the progress of a transaction, unless it already went further.
+    pub fn publish(&self, progress: Progress) {
+        let mut channels = self.channels.lock().unwrap();
+        prune(&mut channels);
+        let channel = channels
+            .entry(progress.signature.clone())
+            .or_insert_with(Channel::new);
+        channel.updated = Instant::now();
+        channel.sender.send_if_modified(|current| {
+            if current
+                .as_ref()
+                .is_some_and(|current| current.stage >= progress.stage)
+            {
+                return false;
+            }
+            *current = Some(progress);
+            true
+        });
+    }
+
+    /// Ends the streams of a transaction the server stopped following, once
+    /// they sent its latest progress.
+    pub fn stop(&self, signature: &str) {
+        self.channels.lock().unwrap().remove(signature);
+    }
+
+    /// Watches the progress of a transaction, which may not be submitted yet.
+    /// Unless it is still `followed`, nothing more will be published about
+    /// it: the receiver only sees the latest progress, or `known` if none was
+    /// published recently, and then its sender is gone.
+    fn subscribe(
+        &self,
+        signature: &str,
+        known: Option<Progress>,
+        followed: bool,
+    ) -> watch::Receiver<Option<Progress>> {
+        let mut channels = self.channels.lock().unwrap();
+        prune(&mut channels);
+        if let Some(channel) = channels.get(signature) {
+            return channel.sender.subscribe();
+        }
+        if !followed {
+            return watch::channel(known).1;
+        }
+        channels
+            .entry(signature.into())
+            .or_insert_with(Channel::new)
+            .sender
+            .subscribe()
+    }
+}
+
+fn prune(channels: &mut HashMap<String, Channel>) {
+    channels.retain(|_, channel| channel.updated.elapsed() < RETENTION);
+}
+
+/// Streams the progress of a transaction as server-sent `progress` events,
+/// until it is finalized or failed, or the server stops following it, its
+/// confirmation having timed out for instance. Only transactions of a
+/// submitted purchase or order can be streamed, as soon as the request is
+/// sent. Stages reached in quick succession may be skipped, the latest one is
+/// always sent.
+#[tracing::instrument(skip(state))]
+pub async fn stream(
+    _: Client,
+    State(state): State<BuyState>,
+    Path(signature): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Error> {
+    info!("streaming transaction progress");
+    let signature = Signature::from_str(&signature)
+        .map_err(|e| Error::Validation(format!("invalid signature {signature}: {e}")))?;
+    let signature = signature.to_string();
+    let (known, followed) = match state.purchases.find_order_by_signature(&signature)? {
+        Some(order) => (
+            Progress::settled(&order),
+            order.status == PurchaseStatus::Pending,
+        ),
+        None => match state.queue.jobs.find_job_by_signature(&signature)? {
+            // a job only fails without an order if it was rejected
+            Some(job) => (None, job.status != JobStatus::Failed),
+            None => {
+                return Err(Error::NotFound(format!(
+                    "no purchase is paid by transaction {signature}"
+                )))
+            }
+        },
+    };
+    let receiver = state.progress.subscribe(&signature, known, followed);
+    let events = stream::unfold(Some((receiver, true)), |next| async move {
+        let (mut receiver, first) = next?;
+        if !first {
+            receiver.changed().await.ok()?;
+        }
+        loop {
+            let progress = receiver.borrow_and_update().clone();
+            if let Some(progress) = progress {
+                let event = Event::default()
+                    .event("progress")
+                    .json_data(&progress)
+                    .expect("progress serializes to json");
+                let next = (!progress.stage.is_final()).then_some((receiver, false));
+                return Some((Ok(event), next));
+            }
+            receiver.changed().await.ok()?;
+        }
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ends_streams_once_no_longer_followed() {
+        let tracker = Tracker::default();
+        let mut followed = tracker.subscribe("sig", None, true);
+        tracker.publish(Progress::new(1, "sig", Stage::Submitted));
+        tracker.stop("sig");
+        followed.changed().await.unwrap();
+        assert_eq!(
+            followed.borrow_and_update().as_ref().unwrap().stage,
+            Stage::Submitted
+        );
+        assert!(followed.changed().await.is_err());
+
+        let known = Progress::new(1, "sig", Stage::Confirmed);
+        let settled = tracker.subscribe("sig", Some(known), false);
+        assert_eq!(settled.borrow().as_ref().unwrap().stage, Stage::Confirmed);
+        assert!(settled.has_changed().is_err());
+        assert!(tracker.channels.lock().unwrap().is_empty());
+    }
+}
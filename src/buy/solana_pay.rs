@@ -0,0 +1,362 @@
+use super::{lookup_commitment, notify, parse_pubkey, price, whitelisted, BuyState, Item};
+use crate::{
+    auth::Client,
+    error::Error,
+    payment::{self, Payment},
+    purchases::{Order, Purchase, PurchaseStatus},
+};
+use anyhow::Context;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use qrcode::{render::svg, QrCode};
+use serde::Deserialize;
+use solana_client::{
+    rpc_client::GetConfirmedSignaturesForAddress2Config, rpc_config::RpcTransactionConfig,
+};
+use solana_sdk::{
+    message::{Message, VersionedMessage},
+    pubkey::Pubkey,
+    signature::{Keypair, Signature},
+    signer::Signer,
+};
+use solana_transaction_status::UiTransactionEncoding;
+use std::{io::Cursor, str::FromStr};
+use tracing::info;
+
+/// Decimals of sol amounts in transfer request urls.
+const SOL_DECIMALS: u8 = 9;
+/// Minimum width and height of rendered qr codes, in pixels.
+const QR_SIZE: u32 = 256;
+
+/// Body of `POST /pay`.
+#[derive(Debug, Deserialize)]
+pub struct RequestParams {
+    #[serde(flatten)]
+    item: Item,
+    /// Shown by the wallet, defaults to the quantity and name of the service.
+    message: Option<String>,
+    /// Included in the paying transaction, and visible on chain.
+    memo: Option<String>,
+}
+
+/// Records a pending order of a single item and returns it with the Solana
+/// Pay transfer request url paying it, labelled with the vendor name. The
+/// buyer pays from their own wallet, the order is matched to the transaction
+/// by its unique `reference`.
+#[tracing::instrument(skip(state))]
+pub async fn create(
+    _: Client,
+    State(state): State<BuyState>,
+    Json(params): Json<RequestParams>,
+) -> Result<(StatusCode, Json<Order>), Error> {
+    info!("creating payment request");
+    // the payer is only known once the request is paid
+    let due = price(&state.vendors, Pubkey::default(), &params.item)?;
+    let vendor = whitelisted(&state.vendors, &due.vendor)?;
+    let service = vendor.service(&params.item.service)?;
+    let decimals = match &due.mint {
+        Some(mint) => payment::mint(&state.client, mint).await?.decimals,
+        None => SOL_DECIMALS,
+    };
+    let reference = Keypair::new().pubkey();
+    let message = params
+        .message
+        .unwrap_or_else(|| format!("{} x {}", params.item.quantity, service.name));
+    let url = url(
+        &due,
+        decimals,
+        &reference,
+        &vendor.name,
+        &message,
+        params.memo.as_deref(),
+    );
+
+    let item = Purchase::item(
+        vendor.wallet_id.clone(),
+        service.id.clone(),
+        params.item.quantity,
+        due.amount,
+        due.mint.map(|mint| mint.to_string()),
+    );
+    let order = Order {
+        reference: Some(reference.to_string()),
+        memo: params.memo,
+        url: Some(url),
+        ..Order::pending(String::new(), String::new(), vec![item])
+    };
+    let id = state
+        .purchases
+        .insert_order(&order)?
+        .context("unpaid order conflicts with another order")?;
+    let order = state
+        .purchases
+        .get_order(id)?
+        .context("recorded order not found")?;
+    Ok((StatusCode::CREATED, Json(order)))
+}
+
+/// Returns the order of a payment request, looking up its payment on chain
+/// while it is pending.
+#[tracing::instrument(skip(state))]
+pub async fn status(
+    _: Client,
+    State(state): State<BuyState>,
+    Path(reference): Path<String>,
+) -> Result<Json<Order>, Error> {
+    info!("checking payment request");
+    let order = find(&state, &reference)?;
+    if order.status != PurchaseStatus::Pending {
+        return Ok(Json(order));
+    }
+    let Some((signature, buyer)) =
+        locate(&state, &parse_pubkey("reference", &reference)?, &order).await?
+    else {
+        return Ok(Json(order));
+    };
+    info!("payment request paid by {signature}");
+    if state
+        .purchases
+        .confirm_order(order.id, &buyer.to_string(), &signature.to_string())?
+    {
+        notify(&state, order.id);
+    }
+    find(&state, &reference).map(Json)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QrFormat {
+    #[default]
+    Svg,
+    Png,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QrParams {
+    #[serde(default)]
+    format: QrFormat,
+}
+
+/// Renders the transfer request url of a payment request as a qr code. Needs
+/// no token, so it can be embedded in a checkout page, the reference is
+/// unguessable.
+#[tracing::instrument(skip(state))]
+pub async fn qr(
+    State(state): State<BuyState>,
+    Path(reference): Path<String>,
+    Query(params): Query<QrParams>,
+) -> Result<Response, Error> {
+    info!("rendering payment request qr code");
+    let url = find(&state, &reference)?
+        .url
+        .ok_or_else(|| not_found(&reference))?;
+    let code = QrCode::new(url.as_bytes()).context("failed to encode qr code")?;
+    Ok(match params.format {
+        QrFormat::Svg => {
+            let image = code
+                .render::<svg::Color>()
+                .min_dimensions(QR_SIZE, QR_SIZE)
+                .build();
+            ([(CONTENT_TYPE, "image/svg+xml")], image).into_response()
+        }
+        QrFormat::Png => {
+            let image = code
+                .render::<image::Luma<u8>>()
+                .min_dimensions(QR_SIZE, QR_SIZE)
+                .build();
+            let mut png = Vec::new();
+            image
+                .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+                .context("failed to encode png")?;
+            ([(CONTENT_TYPE, "image/png")], png).into_response()
+        }
+    })
+}
+
+/// Builds a `solana:` transfer request url, see
+/// <https://docs.solanapay.com/spec#transfer-request>.
+fn url(
+    payment: &Payment,
+    decimals: u8,
+    reference: &Pubkey,
+    label: &str,
+    message: &str,
+    memo: Option<&str>,
+) -> String {
+    let mut url = format!(
+        "solana:{}?amount={}",
+        payment.vendor,
+        decimal(payment.amount, decimals)
+    );
+    if let Some(mint) = &payment.mint {
+        url.push_str(&format!("&spl-token={mint}"));
+    }
+    url.push_str(&format!("&reference={reference}"));
+    let encode = |value| utf8_percent_encode(value, NON_ALPHANUMERIC);
+    url.push_str(&format!(
+        "&label={}&message={}",
+        encode(label),
+        encode(message)
+    ));
+    if let Some(memo) = memo {
+        url.push_str(&format!("&memo={}", encode(memo)));
+    }
+    url
+}
+
+/// Formats an amount in base units as a decimal, `1500000` with 6 decimals
+/// is `1.5`.
+fn decimal(amount: u64, decimals: u8) -> String {
+    let decimals = usize::from(decimals);
+    let digits = format!("{amount:0>width$}", width = decimals + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals);
+    match fraction.trim_end_matches('0') {
+        "" => whole.into(),
+        fraction => format!("{whole}.{fraction}"),
+    }
+}
+
+/// Finds the oldest successful transaction referencing the request which
+/// pays its order, and returns its signature and payer.
+pub async fn locate(
+    state: &BuyState,
+    reference: &Pubkey,
+    order: &Order,
+) -> Result<Option<(Signature, Pubkey)>, Error> {
+    let config = GetConfirmedSignaturesForAddress2Config {
+        commitment: Some(lookup_commitment(&state.client)),
+        ..Default::default()
+    };
+    let signatures = state
+        .client
+        .get_signatures_for_address_with_config(reference, config)
+        .await?;
+    // the rpc returns newest first, check the oldest payment first
+    for status in signatures
+        .iter()
+        .rev()
+        .filter(|status| status.err.is_none())
+    {
+        let signature =
+            Signature::from_str(&status.signature).context("rpc returned an invalid signature")?;
+        let Some(message) = transaction(state, &signature).await? else {
+            continue;
+        };
+        if let Some(buyer) = payer(state, &message, order).await? {
+            return Ok(Some((signature, buyer)));
+        }
+    }
+    Ok(None)
+}
+
+/// Fetches the message of a successful transaction. Versioned transactions
+/// may load accounts from lookup tables and are skipped, wallets pay transfer
+/// requests with legacy ones.
+pub async fn transaction(
+    state: &BuyState,
+    signature: &Signature,
+) -> Result<Option<Message>, Error> {
+    let config = RpcTransactionConfig {
+        encoding: Some(UiTransactionEncoding::Base64),
+        commitment: Some(lookup_commitment(&state.client)),
+        max_supported_transaction_version: Some(0),
+    };
+    let transaction = state
+        .client
+        .get_transaction_with_config(signature, config)
+        .await?
+        .transaction;
+    if transaction.meta.is_some_and(|meta| meta.err.is_some()) {
+        return Ok(None);
+    }
+    match transaction
+        .transaction
+        .decode()
+        .map(|transaction| transaction.message)
+    {
+        Some(VersionedMessage::Legacy(message)) => Ok(Some(message)),
+        _ => Ok(None),
+    }
+}
+
+/// Returns the payer of a message if its single transfer pays the order's
+/// only item.
+pub async fn payer(
+    state: &BuyState,
+    message: &Message,
+    order: &Order,
+) -> Result<Option<Pubkey>, Error> {
+    let payments = match payment::payments(&state.client, message).await {
+        Ok(payments) => payments,
+        Err(Error::Validation(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let ([paid], [item]) = (&payments[..], &order.items[..]) else {
+        return Ok(None);
+    };
+    let pays = paid.vendor.to_string() == item.vendor
+        && paid.amount == item.amount
+        && paid.mint.map(|mint| mint.to_string()) == item.mint;
+    Ok(pays.then_some(paid.buyer))
+}
+
+fn find(state: &BuyState, reference: &str) -> Result<Order, Error> {
+    state
+        .purchases
+        .find_order(reference)?
+        .ok_or_else(|| not_found(reference))
+}
+
+fn not_found(reference: &str) -> Error {
+    Error::NotFound(format!("payment request {reference} not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_base_units_as_decimals() {
+        assert_eq!(decimal(1_500_000, 6), "1.5");
+        assert_eq!(decimal(5, 9), "0.000000005");
+        assert_eq!(decimal(2_000_000_000, 9), "2");
+        assert_eq!(decimal(0, 9), "0");
+        assert_eq!(decimal(42, 0), "42");
+    }
+
+    #[test]
+    fn builds_transfer_request_urls() {
+        let (vendor, mint, reference) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let mut payment = Payment {
+            buyer: Pubkey::default(),
+            vendor,
+            amount: 1_500_000_000,
+            mint: None,
+        };
+        assert_eq!(
+            url(&payment, 9, &reference, "Coffee shop", "2 x tea", None),
+            format!(
+                "solana:{vendor}?amount=1.5&reference={reference}\
+                &label=Coffee%20shop&message=2%20x%20tea"
+            )
+        );
+        payment.mint = Some(mint);
+        payment.amount = 250;
+        assert_eq!(
+            url(&payment, 2, &reference, "shop", "", Some("order #1")),
+            format!(
+                "solana:{vendor}?amount=2.5&spl-token={mint}&reference={reference}\
+                &label=shop&message=&memo=order%20%231"
+            )
+        );
+    }
+}
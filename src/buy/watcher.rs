@@ -0,0 +1,413 @@
+use super::{lookup_commitment, notify, parse_pubkey, record_receipt, solana_pay, BuyState};
+use crate::{
+    error::Error,
+    payment,
+    purchases::{self, Order, PurchaseStatus, Receipt},
+    vendors::VendorStatus,
+};
+use anyhow::Context;
+use solana_client::{
+    rpc_client::GetConfirmedSignaturesForAddress2Config,
+    rpc_response::RpcConfirmedTransactionStatusWithSignature,
+};
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+use std::{collections::HashMap, str::FromStr, time::Duration};
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+
+/// Signatures fetched per request, the maximum allowed by rpc nodes.
+const SIGNATURES_PER_PAGE: usize = 1000;
+/// Allowed difference, in seconds, between the clock of the server and block
+/// times.
+const CLOCK_SKEW: i64 = 300;
+
+/// Reconciles pending orders with the transactions paying whitelisted
+/// vendors, every `interval`, whoever submitted them: Solana Pay requests are
+/// matched by reference, and pending purchases by signature.
+///
+/// Only addresses with pending orders are polled, the newest signature seen
+/// per address is kept in memory so each poll only fetches new transactions.
+/// Orders still pending after `expiry` are failed.
+pub async fn run(state: BuyState, interval: Duration, expiry: Duration) {
+    info!("watching payments every {interval:?}");
+    let mut seen = HashMap::new();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = poll(&state, &mut seen, expiry).await {
+            warn!("payment watcher failed: {e}");
+        }
+    }
+}
+
+async fn poll(
+    state: &BuyState,
+    seen: &mut HashMap<Pubkey, Signature>,
+    expiry: Duration,
+) -> Result<(), Error> {
+    let mut pending = state.purchases.pending_orders()?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    for vendor in state.vendors.list()? {
+        if vendor.status != VendorStatus::Approved {
+            continue;
+        }
+        for address in addresses(&vendor.wallet_id, &pending)? {
+            if let Err(e) = poll_address(state, &address, seen, &mut pending).await {
+                warn!("failed to poll payments to {address}: {e}");
+            }
+        }
+    }
+    expire(state, &pending, expiry).await
+}
+
+/// The wallet of a vendor with pending orders, and its token accounts of the
+/// mints they are priced in, under either token program since the mints are
+/// not looked up.
+fn addresses(wallet_id: &str, pending: &[Order]) -> Result<Vec<Pubkey>, Error> {
+    let items: Vec<_> = pending
+        .iter()
+        .flat_map(|order| &order.items)
+        .filter(|item| item.vendor == wallet_id)
+        .collect();
+    if items.is_empty() {
+        return Ok(Vec::new());
+    }
+    let wallet = parse_pubkey("vendor", wallet_id)?;
+    let mut addresses = vec![wallet];
+    for mint in items.iter().filter_map(|item| item.mint.as_deref()) {
+        let mint = parse_pubkey("mint", mint)?;
+        for program in &payment::TOKEN_PROGRAMS {
+            let account = get_associated_token_address_with_program_id(&wallet, &mint, program);
+            if !addresses.contains(&account) {
+                addresses.push(account);
+            }
+        }
+    }
+    Ok(addresses)
+}
+
+/// Matches the transactions of an address since the last poll to pending
+/// orders, removing those it settles.
+async fn poll_address(
+    state: &BuyState,
+    address: &Pubkey,
+    seen: &mut HashMap<Pubkey, Signature>,
+    pending: &mut Vec<Order>,
+) -> Result<(), Error> {
+    let since = pending
+        .iter()
+        .map(|order| order.created_at)
+        .min()
+        .unwrap_or_default();
+    let signatures = signatures(
+        state,
+        address,
+        seen.get(address).copied(),
+        since - CLOCK_SKEW,
+    )
+    .await?;
+    // the rpc returns newest first, check the oldest payment first
+    for status in signatures.iter().rev() {
+        let signature =
+            Signature::from_str(&status.signature).context("rpc returned an invalid signature")?;
+        if let Some(i) = pending.iter().position(|o| o.signature == status.signature) {
+            let order = pending.remove(i);
+            let settled = match &status.err {
+                None => {
+                    let confirmed =
+                        state
+                            .purchases
+                            .confirm_order(order.id, &order.buyer, &order.signature)?;
+                    if confirmed {
+                        let receipt = Receipt {
+                            commitment: status.confirmation_status.clone(),
+                            slot: Some(status.slot),
+                            fee: None,
+                        };
+                        record_receipt(state, order.id, &receipt);
+                    }
+                    confirmed
+                }
+                Some(err) => state.purchases.set_order_status(
+                    order.id,
+                    PurchaseStatus::Failed,
+                    Some(&format!("transaction failed: {err}")),
+                )?,
+            };
+            if settled {
+                info!("order {} settled by {}", order.id, status.signature);
+                notify(state, order.id);
+            }
+            continue;
+        }
+        if status.err.is_some() {
+            continue;
+        }
+        settle_request(state, &signature, pending).await?;
+    }
+    if let Some(newest) = signatures.first() {
+        let signature =
+            Signature::from_str(&newest.signature).context("rpc returned an invalid signature")?;
+        seen.insert(*address, signature);
+    }
+    Ok(())
+}
+
+/// Fetches the signatures of an address, newest first, page by page back to
+/// `until`, or to the first transaction processed before `since`, a unix
+/// timestamp, which can not pay a pending order.
+async fn signatures(
+    state: &BuyState,
+    address: &Pubkey,
+    until: Option<Signature>,
+    since: i64,
+) -> Result<Vec<RpcConfirmedTransactionStatusWithSignature>, Error> {
+    let mut signatures = Vec::new();
+    let mut before = None;
+    loop {
+        let config = GetConfirmedSignaturesForAddress2Config {
+            before,
+            until,
+            limit: Some(SIGNATURES_PER_PAGE),
+            commitment: Some(lookup_commitment(&state.client)),
+        };
+        let page = state
+            .client
+            .get_signatures_for_address_with_config(address, config)
+            .await?;
+        let last_page = page.len() < SIGNATURES_PER_PAGE;
+        let older = page
+            .iter()
+            .position(|status| status.block_time.is_some_and(|time| time < since));
+        match older {
+            Some(i) => {
+                signatures.extend(page.into_iter().take(i));
+                return Ok(signatures);
+            }
+            None => signatures.extend(page),
+        }
+        match signatures.last() {
+            Some(oldest) if !last_page => {
+                before = Some(
+                    Signature::from_str(&oldest.signature)
+                        .context("rpc returned an invalid signature")?,
+                );
+            }
+            _ => return Ok(signatures),
+        }
+    }
+}
+
+/// Fails the orders pending for longer than `expiry`, after looking up their
+/// payment one last time. Submitted transactions can not land once their
+/// blockhash expired, and unpaid payment requests are abandoned.
+async fn expire(state: &BuyState, pending: &[Order], expiry: Duration) -> Result<(), Error> {
+    let deadline = purchases::now() - expiry.as_secs() as i64;
+    for order in pending.iter().filter(|order| order.created_at < deadline) {
+        let settled = if order.signature.is_empty() {
+            let reference = order.reference.as_deref().unwrap_or_default();
+            match solana_pay::locate(state, &parse_pubkey("reference", reference)?, order).await? {
+                Some((signature, buyer)) => state.purchases.confirm_order(
+                    order.id,
+                    &buyer.to_string(),
+                    &signature.to_string(),
+                )?,
+                None => state.purchases.set_order_status(
+                    order.id,
+                    PurchaseStatus::Failed,
+                    Some("payment request expired unpaid"),
+                )?,
+            }
+        } else {
+            let signature =
+                Signature::from_str(&order.signature).context("order has an invalid signature")?;
+            let status = state
+                .client
+                .get_signature_statuses_with_history(&[signature])
+                .await?
+                .value
+                .pop()
+                .flatten();
+            match status {
+                Some(status) => match status.err {
+                    None => {
+                        state
+                            .purchases
+                            .confirm_order(order.id, &order.buyer, &order.signature)?
+                    }
+                    Some(err) => state.purchases.set_order_status(
+                        order.id,
+                        PurchaseStatus::Failed,
+                        Some(&format!("transaction failed: {err}")),
+                    )?,
+                },
+                None => state.purchases.set_order_status(
+                    order.id,
+                    PurchaseStatus::Failed,
+                    Some("transaction expired before it was confirmed"),
+                )?,
+            }
+        };
+        if settled {
+            info!("expired order {}", order.id);
+            notify(state, order.id);
+        }
+    }
+    Ok(())
+}
+
+/// Confirms the unpaid Solana Pay request a transaction pays, if any. The
+/// transaction must include its reference, memos are chosen by clients and
+/// may be shared by unrelated payments.
+async fn settle_request(
+    state: &BuyState,
+    signature: &Signature,
+    pending: &mut Vec<Order>,
+) -> Result<(), Error> {
+    let Some(message) = solana_pay::transaction(state, signature).await? else {
+        return Ok(());
+    };
+    let keys: Vec<_> = message
+        .account_keys
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let candidates = pending.iter().enumerate().filter(|(_, order)| {
+        order.signature.is_empty() && order.reference.as_ref().is_some_and(|r| keys.contains(r))
+    });
+    for (i, order) in candidates {
+        if let Some(buyer) = solana_pay::payer(state, &message, order).await? {
+            // the transaction may already pay another order
+            if state.purchases.confirm_order(
+                order.id,
+                &buyer.to_string(),
+                &signature.to_string(),
+            )? {
+                info!("payment request of order {} paid by {signature}", order.id);
+                notify(state, order.id);
+            }
+            pending.remove(i);
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::tests::state, *};
+    use axum::{extract::State, routing::post, Json, Router, Server};
+    use serde_json::{json, Value};
+    use solana_client::nonblocking::rpc_client::RpcClient;
+    use solana_sdk::{
+        instruction::AccountMeta,
+        signature::{Keypair, Signer},
+        system_instruction,
+        transaction::Transaction,
+    };
+    use std::sync::Arc;
+
+    /// An rpc node answering `getTransaction` with `transactions`, keyed by
+    /// signature.
+    async fn rpc(transactions: &[&Transaction]) -> RpcClient {
+        async fn handle(
+            State(transactions): State<Arc<HashMap<String, String>>>,
+            Json(request): Json<Value>,
+        ) -> Json<Value> {
+            let result = match request["method"].as_str() {
+                Some("getVersion") => json!({ "solana-core": "1.15.2" }),
+                Some("getTransaction") => {
+                    let signature = request["params"][0].as_str().unwrap();
+                    json!({
+                        "slot": 7,
+                        "blockTime": null,
+                        "transaction": [transactions[signature], "base64"],
+                        "meta": null,
+                    })
+                }
+                method => panic!("unexpected rpc method {method:?}"),
+            };
+            Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+        }
+        let transactions: HashMap<_, _> = transactions
+            .iter()
+            .map(|transaction| {
+                let encoded = base64::encode(bincode::serialize(transaction).unwrap());
+                (transaction.signatures[0].to_string(), encoded)
+            })
+            .collect();
+        let router = Router::new()
+            .route("/", post(handle))
+            .with_state(Arc::new(transactions));
+        let server =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(router.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        RpcClient::new(url)
+    }
+
+    /// A transfer to `vendor`, referencing `reference` if any, and with a memo.
+    fn pay(
+        buyer: &Keypair,
+        vendor: &Pubkey,
+        lamports: u64,
+        reference: Option<&Pubkey>,
+    ) -> Transaction {
+        let mut transfer = system_instruction::transfer(&buyer.pubkey(), vendor, lamports);
+        transfer
+            .accounts
+            .extend(reference.map(|reference| AccountMeta::new_readonly(*reference, false)));
+        let memo = spl_memo::build_memo(b"table 4", &[]);
+        let mut transaction = Transaction::new_with_payer(&[transfer, memo], Some(&buyer.pubkey()));
+        transaction.sign(&[buyer], Default::default());
+        transaction
+    }
+
+    #[tokio::test]
+    async fn matches_payment_requests_by_reference() {
+        let (buyer, vendor) = (Keypair::new(), Pubkey::new_unique());
+        let (reference, other) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let memo_only = pay(&buyer, &vendor, 5000, None);
+        let underpaid = pay(&buyer, &vendor, 4000, Some(&reference));
+        let paid = pay(&buyer, &vendor, 5000, Some(&reference));
+        let state = BuyState {
+            client: rpc(&[&memo_only, &underpaid, &paid]).await.into(),
+            ..state()
+        };
+        let mut pending = Vec::new();
+        for reference in [reference, other] {
+            let item =
+                purchases::Purchase::item(vendor.to_string(), "coffee".into(), 1, 5000, None);
+            let order = Order {
+                reference: Some(reference.to_string()),
+                ..Order::pending(String::new(), String::new(), vec![item])
+            };
+            let id = state.purchases.insert_order(&order).unwrap().unwrap();
+            pending.push(state.purchases.get_order(id).unwrap().unwrap());
+        }
+        let (first, second) = (pending[0].id, pending[1].id);
+
+        for unpaid in [&memo_only, &underpaid] {
+            settle_request(&state, &unpaid.signatures[0], &mut pending)
+                .await
+                .unwrap();
+            assert_eq!(pending.len(), 2);
+        }
+        settle_request(&state, &paid.signatures[0], &mut pending)
+            .await
+            .unwrap();
+        let ids: Vec<_> = pending.iter().map(|order| order.id).collect();
+        assert_eq!(ids, [second]);
+
+        let order = state.purchases.get_order(first).unwrap().unwrap();
+        assert_eq!(order.status, PurchaseStatus::Confirmed);
+        assert_eq!(order.buyer, buyer.pubkey().to_string());
+        assert_eq!(order.signature, paid.signatures[0].to_string());
+        let other = state.purchases.get_order(second).unwrap().unwrap();
+        assert_eq!(other.status, PurchaseStatus::Pending);
+    }
+}
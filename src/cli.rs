@@ -0,0 +1,316 @@
+use crate::{
+    auth,
+    config::{Config, Overrides},
+    store,
+    vendors::{
+        self,
+        bulk::{self, Format},
+        Service, Vendor, VendorStatus,
+    },
+};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+
+/// http server that returns a list of whitelisted vendors and executes transactions
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub overrides: Overrides,
+    /// Defaults to `serve`
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the http server
+    Serve,
+    /// Manage the vendor whitelist in the configured store
+    #[command(subcommand)]
+    Vendors(VendorsCommand),
+    /// Check the configured rpc endpoint is reachable and serves the expected cluster
+    CheckRpc,
+    /// Generate an api token, its hash goes in the `[auth]` configuration
+    GenerateToken,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum VendorsCommand {
+    /// Print every vendor with its status
+    List,
+    /// Whitelist a vendor
+    Add {
+        #[arg(long)]
+        wallet_id: String,
+        #[arg(long)]
+        name: String,
+        #[arg(long, default_value = "")]
+        address: String,
+        /// `<ID>=<PRICE>` in lamports, or `<ID>=<PRICE>@<MINT>` in base units
+        /// of an accepted mint. Can be repeated
+        #[arg(long = "service")]
+        services: Vec<Service>,
+        /// Spl token mint the vendor accepts, can be repeated
+        #[arg(long = "accept-mint")]
+        accepted_mints: Vec<String>,
+    },
+    /// Remove a vendor from the whitelist
+    Remove { wallet_id: String },
+    /// Approve a vendor application
+    Approve { wallet_id: String },
+    /// Reject a vendor application, or revoke an approved vendor
+    Reject { wallet_id: String },
+    /// Whitelist every vendor of a json array or csv file, `-` reads from stdin
+    Import {
+        file: PathBuf,
+        #[arg(long, default_value = "json")]
+        format: Format,
+        /// Import nothing unless every vendor can be imported
+        #[arg(long)]
+        atomic: bool,
+    },
+    /// Write every vendor as a json array or csv file, to stdout by default
+    Export {
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        #[arg(long, default_value = "json")]
+        format: Format,
+    },
+}
+
+pub fn vendors(config: &Config, command: VendorsCommand) -> Result<()> {
+    let store = store::open(config.storage.kind, &config.storage.path)?.vendors;
+    match command {
+        VendorsCommand::List => {
+            for vendor in store.list()? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    vendor.wallet_id,
+                    vendor.status.as_str(),
+                    vendor.name,
+                    vendor.address,
+                    vendor
+                        .services
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(",")
+                );
+            }
+        }
+        VendorsCommand::Add {
+            wallet_id,
+            name,
+            address,
+            services,
+            accepted_mints,
+        } => {
+            let vendor = vendors::add(
+                &store,
+                Vendor {
+                    wallet_id,
+                    name,
+                    address,
+                    services,
+                    accepted_mints,
+                    status: VendorStatus::Approved,
+                },
+            )?;
+            println!("added {}", vendor.wallet_id);
+        }
+        VendorsCommand::Remove { wallet_id } => {
+            vendors::delete(&store, &wallet_id)?;
+            println!("removed {wallet_id}");
+        }
+        VendorsCommand::Approve { wallet_id } => {
+            vendors::set_status(&store, &wallet_id, VendorStatus::Approved)?;
+            println!("approved {wallet_id}");
+        }
+        VendorsCommand::Reject { wallet_id } => {
+            vendors::set_status(&store, &wallet_id, VendorStatus::Rejected)?;
+            println!("rejected {wallet_id}");
+        }
+        VendorsCommand::Import {
+            file,
+            format,
+            atomic,
+        } => {
+            let mut content = Vec::new();
+            if file.as_os_str() == "-" {
+                io::stdin().read_to_end(&mut content)?;
+            } else {
+                File::open(&file)
+                    .with_context(|| format!("failed to open {}", file.display()))?
+                    .read_to_end(&mut content)?;
+            }
+            let report = bulk::import(&store, bulk::parse(format, &content)?, atomic)?;
+            for row in &report.rows {
+                let wallet_id = row.wallet_id.as_deref().unwrap_or("-");
+                match &row.error {
+                    Some(error) => eprintln!(
+                        "row {}: {wallet_id}: {}",
+                        row.row,
+                        error["error"].as_str().unwrap_or_default()
+                    ),
+                    None if row.imported => println!("added {wallet_id}"),
+                    None => {}
+                }
+            }
+            if report.failed > 0 {
+                bail!(
+                    "{} vendors could not be imported, {} imported",
+                    report.failed,
+                    report.imported
+                );
+            }
+        }
+        VendorsCommand::Export { output, format } => {
+            let mut writer: Box<dyn Write> = match &output {
+                Some(path) => Box::new(
+                    File::create(path)
+                        .with_context(|| format!("failed to create {}", path.display()))?,
+                ),
+                None => Box::new(io::stdout()),
+            };
+            bulk::write(format, &store.list()?, &mut writer)?;
+            if format == Format::Json {
+                writeln!(writer)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn check_rpc(config: &Config) -> Result<()> {
+    let url = config.rpc_url();
+    let client = config.rpc_client();
+    let version = client
+        .get_version()
+        .await
+        .with_context(|| format!("failed to reach {url}"))?;
+    let genesis_hash = client.get_genesis_hash().await?.to_string();
+    let slot = client.get_slot().await?;
+    println!("endpoint:     {url}");
+    println!("version:      {}", version.solana_core);
+    println!("genesis hash: {genesis_hash}");
+    println!("slot:         {slot}");
+    match config.rpc.cluster.genesis_hash() {
+        Some(expected) if expected != genesis_hash => bail!(
+            "{url} does not serve {:?}, expected genesis hash {expected}",
+            config.rpc.cluster
+        ),
+        _ => Ok(()),
+    }
+}
+
+pub fn generate_token() {
+    let (token, hash) = auth::generate();
+    println!("token: {token}");
+    println!("hash:  {hash}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StoreKind;
+    use clap::CommandFactory;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(["hackathon-backend"].iter().chain(args))
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        Cli::command().debug_assert();
+        assert!(parse(&[]).unwrap().command.is_none());
+        assert!(matches!(
+            parse(&["serve"]).unwrap().command,
+            Some(Command::Serve)
+        ));
+        assert!(parse(&["unknown"]).is_err());
+
+        let mint = solana_sdk::pubkey::Pubkey::new_unique().to_string();
+        let cli = parse(&[
+            "vendors",
+            "add",
+            "--wallet-id",
+            "wallet",
+            "--name",
+            "Café",
+            "--service",
+            "coffee=5000",
+            "--service",
+            &format!("tea=3@{mint}"),
+            "--accept-mint",
+            &mint,
+            "--store",
+            "memory",
+        ])
+        .unwrap();
+        assert_eq!(cli.overrides.store, Some(StoreKind::Memory));
+        let Some(Command::Vendors(VendorsCommand::Add {
+            wallet_id,
+            services,
+            accepted_mints,
+            ..
+        })) = cli.command
+        else {
+            panic!("expected vendors add, got {:?}", cli.command);
+        };
+        assert_eq!(wallet_id, "wallet");
+        let prices: Vec<_> = services
+            .iter()
+            .map(|service| (service.id.as_str(), service.price, service.mint.as_deref()))
+            .collect();
+        assert_eq!(
+            prices,
+            [("coffee", 5000, None), ("tea", 3, Some(mint.as_str()))]
+        );
+        assert_eq!(accepted_mints, [mint]);
+        assert!(parse(&["vendors", "add", "--wallet-id", "wallet"]).is_err());
+        assert!(parse(&[
+            "vendors",
+            "add",
+            "--name",
+            "n",
+            "--wallet-id",
+            "w",
+            "--service",
+            "coffee"
+        ])
+        .is_err());
+
+        let cli = parse(&["vendors", "import", "-", "--format", "csv", "--atomic"]).unwrap();
+        let Some(Command::Vendors(VendorsCommand::Import {
+            file,
+            format,
+            atomic,
+        })) = cli.command
+        else {
+            panic!("expected vendors import, got {:?}", cli.command);
+        };
+        assert_eq!(
+            (file, format, atomic),
+            (PathBuf::from("-"), Format::Csv, true)
+        );
+
+        let cli = parse(&["vendors", "export", "-o", "vendors.csv"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Vendors(VendorsCommand::Export {
+                output: Some(_),
+                format: Format::Json
+            }))
+        ));
+        assert!(matches!(
+            parse(&["vendors", "approve", "wallet"]).unwrap().command,
+            Some(Command::Vendors(VendorsCommand::Approve { wallet_id })) if wallet_id == "wallet"
+        ));
+    }
+}
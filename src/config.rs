@@ -0,0 +1,455 @@
+use crate::{
+    buy::{jobs::Workers, Confirmation},
+    webhooks::Retries,
+};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::commitment_config::CommitmentConfig;
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::info;
+
+/// Read when `--config` is not given, if present.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Shortest delay between two status checks of a submitted transaction.
+const MIN_POLL_INTERVAL_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Cluster {
+    MainnetBeta,
+    Testnet,
+    Devnet,
+    Localnet,
+}
+
+impl Cluster {
+    pub fn rpc_url(self) -> &'static str {
+        match self {
+            Self::MainnetBeta => "https://api.mainnet-beta.solana.com",
+            Self::Testnet => "https://api.testnet.solana.com",
+            Self::Devnet => "https://api.devnet.solana.com",
+            Self::Localnet => "http://127.0.0.1:8899",
+        }
+    }
+
+    /// Known genesis hash of public clusters, used to check the rpc endpoint.
+    pub fn genesis_hash(self) -> Option<&'static str> {
+        match self {
+            Self::MainnetBeta => Some("5eykt4UsFv8P8NJdTREpY1vzqKqZKvdpKuc147dw2N9d"),
+            Self::Testnet => Some("4uhcVJyU9pJkvQyS88uRDiswHXSCkY3zQawwpjk2NsNY"),
+            Self::Devnet => Some("EtWTRABZaYq6iMfeYKouRu166VU2xqa1wcaWoxPkrZBG"),
+            Self::Localnet => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Commitment {
+    Processed,
+    Confirmed,
+    Finalized,
+}
+
+impl From<Commitment> for CommitmentConfig {
+    fn from(value: Commitment) -> Self {
+        match value {
+            Commitment::Processed => Self::processed(),
+            Commitment::Confirmed => Self::confirmed(),
+            Commitment::Finalized => Self::finalized(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    Memory,
+    Sqlite,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub rpc: RpcConfig,
+    pub confirmation: ConfirmationConfig,
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub watcher: WatcherConfig,
+    pub webhooks: WebhooksConfig,
+    pub jobs: JobsConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: IpAddr,
+    pub port: u16,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    pub cluster: Cluster,
+    /// Overrides the public endpoint of `cluster`.
+    pub url: Option<String>,
+    pub commitment: Commitment,
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfirmationConfig {
+    pub timeout_secs: u64,
+    pub poll_interval_ms: u64,
+    pub max_poll_interval_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub kind: StoreKind,
+    pub path: PathBuf,
+}
+
+/// Hex encoded sha256 hashes of the accepted api tokens, see `generate-token`.
+/// The server refuses to start if a role has no token, unless
+/// `insecure_no_auth` opens its routes to everyone.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Required to manage vendors and read purchases.
+    pub admin_token_hashes: Vec<String>,
+    /// Required to submit purchases.
+    pub client_token_hashes: Vec<String>,
+    /// Leaves the routes of a role without any token open, for development.
+    pub insecure_no_auth: bool,
+    /// Time buyers have to sign a challenge.
+    pub challenge_ttl_secs: u64,
+    /// Lifetime of the session opened by a signed challenge.
+    pub session_ttl_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatcherConfig {
+    /// Time between two polls of the transactions paying vendors, 0 disables
+    /// the watcher.
+    pub interval_secs: u64,
+    /// Age at which pending orders are failed if still unpaid.
+    pub expire_after_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    pub max_attempts: u32,
+    pub backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// Purchases processed concurrently.
+    pub workers: usize,
+    pub max_attempts: u32,
+    pub backoff_secs: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: Ipv4Addr::LOCALHOST.into(),
+            port: 3030,
+        }
+    }
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        Self {
+            cluster: Cluster::Devnet,
+            url: None,
+            commitment: Commitment::Confirmed,
+            timeout_secs: 30,
+        }
+    }
+}
+
+impl Default for ConfirmationConfig {
+    fn default() -> Self {
+        let confirmation = Confirmation::default();
+        Self {
+            timeout_secs: confirmation.timeout.as_secs(),
+            poll_interval_ms: confirmation.poll_interval.as_millis() as u64,
+            max_poll_interval_ms: confirmation.max_poll_interval.as_millis() as u64,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            admin_token_hashes: Vec::new(),
+            client_token_hashes: Vec::new(),
+            insecure_no_auth: false,
+            challenge_ttl_secs: 300,
+            session_ttl_secs: 900,
+        }
+    }
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 30,
+            expire_after_secs: 900,
+        }
+    }
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        let retries = Retries::default();
+        Self {
+            max_attempts: retries.max_attempts,
+            backoff_secs: retries.backoff.as_secs(),
+            max_backoff_secs: retries.max_backoff.as_secs(),
+            timeout_secs: retries.timeout.as_secs(),
+        }
+    }
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        let workers = Workers::default();
+        Self {
+            workers: workers.count,
+            max_attempts: workers.max_attempts,
+            backoff_secs: workers.backoff.as_secs(),
+            max_backoff_secs: workers.max_backoff.as_secs(),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            kind: StoreKind::Sqlite,
+            path: "vendors.db".into(),
+        }
+    }
+}
+
+/// Settings which can be given on the command line or through the
+/// `HACKATHON_` prefixed environment, taking precedence over the
+/// configuration file.
+#[derive(Debug, clap::Args)]
+pub struct Overrides {
+    /// TOML configuration file, `config.toml` is read if present
+    #[arg(long, env = "HACKATHON_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+    #[arg(long, env = "HACKATHON_HOST", global = true)]
+    pub host: Option<IpAddr>,
+    #[arg(long, env = "HACKATHON_PORT", global = true)]
+    pub port: Option<u16>,
+    #[arg(long, env = "HACKATHON_CLUSTER", global = true)]
+    pub cluster: Option<Cluster>,
+    /// Defaults to the public endpoint of the cluster
+    #[arg(long, env = "HACKATHON_RPC_URL", global = true)]
+    pub rpc_url: Option<String>,
+    #[arg(long, env = "HACKATHON_COMMITMENT", global = true)]
+    pub commitment: Option<Commitment>,
+    #[arg(long, env = "HACKATHON_RPC_TIMEOUT_SECS", global = true)]
+    pub rpc_timeout_secs: Option<u64>,
+    /// Time to wait for a purchase to be confirmed before answering it is pending
+    #[arg(long, env = "HACKATHON_CONFIRM_TIMEOUT_SECS", global = true)]
+    pub confirm_timeout_secs: Option<u64>,
+    #[arg(long, env = "HACKATHON_STORE", global = true)]
+    pub store: Option<StoreKind>,
+    #[arg(long, env = "HACKATHON_DATABASE_PATH", global = true)]
+    pub database_path: Option<PathBuf>,
+    /// Comma separated, replaces the hashes of the configuration file
+    #[arg(
+        long,
+        env = "HACKATHON_ADMIN_TOKEN_HASHES",
+        global = true,
+        value_delimiter = ','
+    )]
+    pub admin_token_hashes: Vec<String>,
+    /// Comma separated, replaces the hashes of the configuration file
+    #[arg(
+        long,
+        env = "HACKATHON_CLIENT_TOKEN_HASHES",
+        global = true,
+        value_delimiter = ','
+    )]
+    pub client_token_hashes: Vec<String>,
+    /// Serve the routes of a role without any token to everyone
+    #[arg(long, env = "HACKATHON_INSECURE_NO_AUTH", global = true)]
+    pub insecure_no_auth: bool,
+    /// Time between two polls of the payments to vendors, 0 disables it
+    #[arg(long, env = "HACKATHON_WATCH_INTERVAL_SECS", global = true)]
+    pub watch_interval_secs: Option<u64>,
+}
+
+impl Config {
+    /// Layers, from lowest to highest precedence: defaults, configuration
+    /// file, environment variables and command line flags.
+    pub fn load(overrides: Overrides) -> Result<Self> {
+        let mut config = match &overrides.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Self::default(),
+        };
+        config.apply(overrides);
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        info!("reading configuration from {}", path.display());
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    fn apply(&mut self, overrides: Overrides) {
+        let Overrides {
+            config: _,
+            host,
+            port,
+            cluster,
+            rpc_url,
+            commitment,
+            rpc_timeout_secs,
+            confirm_timeout_secs,
+            store,
+            database_path,
+            admin_token_hashes,
+            client_token_hashes,
+            insecure_no_auth,
+            watch_interval_secs,
+        } = overrides;
+        self.server.host = host.unwrap_or(self.server.host);
+        self.server.port = port.unwrap_or(self.server.port);
+        if let Some(cluster) = cluster {
+            // the file endpoint may serve another cluster
+            self.rpc.cluster = cluster;
+            self.rpc.url = None;
+        }
+        self.rpc.url = rpc_url.or(self.rpc.url.take());
+        self.rpc.commitment = commitment.unwrap_or(self.rpc.commitment);
+        self.rpc.timeout_secs = rpc_timeout_secs.unwrap_or(self.rpc.timeout_secs);
+        self.confirmation.timeout_secs =
+            confirm_timeout_secs.unwrap_or(self.confirmation.timeout_secs);
+        self.storage.kind = store.unwrap_or(self.storage.kind);
+        if let Some(path) = database_path {
+            self.storage.path = path;
+        }
+        if !admin_token_hashes.is_empty() {
+            self.auth.admin_token_hashes = admin_token_hashes;
+        }
+        if !client_token_hashes.is_empty() {
+            self.auth.client_token_hashes = client_token_hashes;
+        }
+        self.auth.insecure_no_auth |= insecure_no_auth;
+        self.watcher.interval_secs = watch_interval_secs.unwrap_or(self.watcher.interval_secs);
+    }
+
+    pub fn rpc_url(&self) -> String {
+        self.rpc
+            .url
+            .clone()
+            .unwrap_or_else(|| self.rpc.cluster.rpc_url().into())
+    }
+
+    pub fn rpc_client(&self) -> RpcClient {
+        RpcClient::new_with_timeout_and_commitment(
+            self.rpc_url(),
+            Duration::from_secs(self.rpc.timeout_secs),
+            self.rpc.commitment.into(),
+        )
+    }
+
+    pub fn retries(&self) -> Retries {
+        Retries {
+            max_attempts: self.webhooks.max_attempts.max(1),
+            backoff: Duration::from_secs(self.webhooks.backoff_secs),
+            max_backoff: Duration::from_secs(self.webhooks.max_backoff_secs),
+            timeout: Duration::from_secs(self.webhooks.timeout_secs),
+        }
+    }
+
+    pub fn workers(&self) -> Workers {
+        Workers {
+            count: self.jobs.workers.max(1),
+            max_attempts: self.jobs.max_attempts.max(1),
+            backoff: Duration::from_secs(self.jobs.backoff_secs),
+            max_backoff: Duration::from_secs(self.jobs.max_backoff_secs),
+        }
+    }
+
+    pub fn confirmation(&self) -> Confirmation {
+        let poll_interval_ms = self.confirmation.poll_interval_ms.max(MIN_POLL_INTERVAL_MS);
+        Confirmation {
+            poll_interval: Duration::from_millis(poll_interval_ms),
+            max_poll_interval: Duration::from_millis(
+                self.confirmation.max_poll_interval_ms.max(poll_interval_ms),
+            ),
+            timeout: Duration::from_secs(self.confirmation.timeout_secs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Cli;
+    use clap::Parser;
+
+    #[test]
+    fn overriding_the_cluster_drops_the_file_endpoint() {
+        let file = || {
+            toml::from_str::<Config>("[rpc]\ncluster = \"devnet\"\nurl = \"http://devnet.local\"")
+                .unwrap()
+        };
+        let cli = |args: &[&str]| Cli::parse_from([&["hackathon-backend"], args].concat());
+
+        let mut config = file();
+        config.apply(cli(&[]).overrides);
+        assert_eq!(config.rpc_url(), "http://devnet.local");
+
+        let mut config = file();
+        config.apply(cli(&["--cluster", "testnet"]).overrides);
+        assert_eq!(config.rpc_url(), Cluster::Testnet.rpc_url());
+
+        let mut config = file();
+        config.apply(cli(&["--cluster", "testnet", "--rpc-url", "http://testnet.local"]).overrides);
+        assert_eq!(config.rpc_url(), "http://testnet.local");
+    }
+
+    #[test]
+    fn clamps_the_confirmation_poll_interval() {
+        let config = toml::from_str::<Config>(
+            "[confirmation]\npoll_interval_ms = 0\nmax_poll_interval_ms = 0",
+        )
+        .unwrap();
+        let confirmation = config.confirmation();
+        assert_eq!(confirmation.poll_interval, Duration::from_millis(100));
+        assert_eq!(confirmation.max_poll_interval, Duration::from_millis(100));
+    }
+}
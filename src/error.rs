@@ -0,0 +1,147 @@
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use solana_client::client_error::{ClientError, ClientErrorKind};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    Validation(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0} is not whitelisted")]
+    NotWhitelisted(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("idempotency key was already used with a different request body")]
+    IdempotencyMismatch,
+    #[error("rpc request failed: {0}")]
+    Rpc(Box<ClientError>),
+    #[error("{0}")]
+    Timeout(String),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Validation(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::NotWhitelisted(_) => StatusCode::FORBIDDEN,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::IdempotencyMismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Rpc(_) => StatusCode::BAD_GATEWAY,
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable identifier of the error kind, meant to be matched on by clients.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Validation(_) => "validation_error",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden(_) => "forbidden",
+            Self::NotFound(_) => "not_found",
+            Self::NotWhitelisted(_) => "not_whitelisted",
+            Self::Conflict(_) => "conflict",
+            Self::IdempotencyMismatch => "idempotency_mismatch",
+            Self::Rpc(_) => "rpc_error",
+            Self::Timeout(_) => "timeout",
+            Self::Internal(_) => "internal_error",
+        }
+    }
+
+    /// JSON body sent to clients.
+    pub fn body(&self) -> serde_json::Value {
+        json!({
+            "error": self.to_string(),
+            "code": self.code(),
+        })
+    }
+}
+
+impl From<ClientError> for Error {
+    fn from(value: ClientError) -> Self {
+        if let Some(err) = value.get_transaction_error() {
+            return Self::Validation(format!("transaction rejected: {err}"));
+        }
+        match value.kind() {
+            ClientErrorKind::Reqwest(e) if e.is_timeout() => {
+                Self::Timeout(format!("rpc request timed out: {e}"))
+            }
+            _ => Self::Rpc(Box::new(value)),
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> axum::response::Response {
+        let mut response = (self.status(), Json(self.body())).into_response();
+        if let Self::Unauthorized(_) = self {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::transaction::TransactionError;
+
+    #[test]
+    fn maps_errors_to_statuses_and_codes() {
+        let cases = [
+            (Error::Validation("v".into()), 400, "validation_error"),
+            (Error::Unauthorized("u".into()), 401, "unauthorized"),
+            (Error::Forbidden("f".into()), 403, "forbidden"),
+            (Error::NotFound("n".into()), 404, "not_found"),
+            (Error::NotWhitelisted("w".into()), 403, "not_whitelisted"),
+            (Error::Conflict("c".into()), 409, "conflict"),
+            (Error::IdempotencyMismatch, 422, "idempotency_mismatch"),
+            (Error::Timeout("t".into()), 504, "timeout"),
+            (Error::Internal(anyhow::anyhow!("i")), 500, "internal_error"),
+        ];
+        for (error, status, code) in cases {
+            assert_eq!(error.status().as_u16(), status, "{error:?}");
+            assert_eq!(
+                error.body(),
+                json!({ "error": error.to_string(), "code": code })
+            );
+        }
+        assert_eq!(
+            Error::NotWhitelisted("vendor x".into()).to_string(),
+            "vendor x is not whitelisted"
+        );
+
+        let response = Error::Unauthorized("missing token".into()).into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        let response = Error::Forbidden("admin only".into()).into_response();
+        assert!(!response.headers().contains_key(header::WWW_AUTHENTICATE));
+    }
+
+    #[test]
+    fn tells_rejected_transactions_from_rpc_failures() {
+        let rejected = Error::from(ClientError::from(TransactionError::AccountNotFound));
+        assert!(matches!(rejected, Error::Validation(e) if e.starts_with("transaction rejected")));
+
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+        let failed = Error::from(ClientError::from(io));
+        assert_eq!(failed.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(failed.code(), "rpc_error");
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{error::Error, purchases, store::IdempotencyStore};
+use anyhow::Context;
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{future::Future, sync::Arc};
+
+pub const HEADER: &str = "idempotency-key";
+const MAX_KEY_LEN: usize = 255;
+/// Seconds after which a key reserved by a request which never completed,
+/// its process having stopped, can be used again. Well beyond the time a
+/// request waits for a confirmation by default.
+const LEASE: i64 = 600;
+
+/// Response of the first request made with an idempotency key, replayed to
+/// every later request using the same key.
+#[derive(Debug, Clone)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub body: serde_json::Value,
+}
+
+impl IntoResponse for StoredResponse {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (status, Json(self.body)).into_response()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IdempotencyRecord {
+    /// Hash of the body of the first request made with the key.
+    pub fingerprint: String,
+    /// Unix timestamp, in seconds, of the reservation of the key.
+    pub reserved_at: i64,
+    /// `None` while the first request is still being processed.
+    pub response: Option<StoredResponse>,
+}
+
+pub type SharedIdempotency = Arc<dyn IdempotencyStore>;
+
+/// Extracts the `Idempotency-Key` header, if any.
+pub fn key(headers: &HeaderMap) -> Result<Option<String>, Error> {
+    let Some(value) = headers.get(HEADER) else {
+        return Ok(None);
+    };
+    match value.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => Ok(Some(key.into())),
+        _ => Err(Error::Validation(format!(
+            "{HEADER} must be a printable string of 1 to {MAX_KEY_LEN} characters"
+        ))),
+    }
+}
+
+pub fn fingerprint(body: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(body))
+}
+
+/// Decides what to do with a request whose key is already known: replay the
+/// stored response, or reject it if the body differs or the first request
+/// is still in flight.
+pub fn replay(record: IdempotencyRecord, fingerprint: &str) -> Result<StoredResponse, Error> {
+    if record.fingerprint != fingerprint {
+        return Err(Error::IdempotencyMismatch);
+    }
+    record.response.ok_or_else(|| {
+        Error::Conflict("a request with this idempotency key is still being processed".into())
+    })
+}
+
+/// Runs `handler`, the processing of a request whose serialized body is
+/// `body`. When an `Idempotency-Key` header is sent, the outcome of the first
+/// request is stored and replayed for every retry with the same key and body.
+/// Server errors are not stored, the key is freed for the retry instead.
+pub async fn run<T>(
+    store: SharedIdempotency,
+    headers: &HeaderMap,
+    body: &[u8],
+    handler: impl Future<Output = Result<(StatusCode, T), Error>> + Send + 'static,
+) -> Result<Response, Error>
+where
+    T: Serialize + Send + 'static,
+{
+    let Some(key) = key(headers)? else {
+        return Ok(handler
+            .await
+            .map(|(status, body)| (status, Json(body)))
+            .into_response());
+    };
+    let fingerprint = fingerprint(body);
+    let now = purchases::now();
+    if let Some(record) = store.reserve(&key, &fingerprint, now, now - LEASE)? {
+        return Ok(replay(record, &fingerprint)?.into_response());
+    }
+
+    // run to completion even if the client goes away, so the key is never
+    // left reserved without a response
+    let response = tokio::spawn(async move {
+        let response = match handler.await {
+            Ok((status, body)) => StoredResponse {
+                status: status.as_u16(),
+                body: serde_json::to_value(body).context("failed to serialize response")?,
+            },
+            Err(e) => StoredResponse {
+                status: e.status().as_u16(),
+                body: e.body(),
+            },
+        };
+        if StatusCode::from_u16(response.status).map_or(true, |s| s.is_server_error()) {
+            store.release(&key)?;
+        } else {
+            store.complete(&key, &response)?;
+        }
+        anyhow::Ok(response)
+    })
+    .await
+    .context("request task failed")??;
+    Ok(response.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use serde_json::json;
+
+    fn headers(key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER, key.parse().unwrap());
+        headers
+    }
+
+    async fn call(store: &SharedIdempotency, status: StatusCode, body: &str) -> u16 {
+        let response = json!({ "body": body });
+        let handler = async move { Ok((status, response)) };
+        run(store.clone(), &headers("key"), body.as_bytes(), handler)
+            .await
+            .unwrap()
+            .status()
+            .as_u16()
+    }
+
+    #[test]
+    fn replays_completed_requests_with_the_same_body() {
+        let record = |response| IdempotencyRecord {
+            fingerprint: fingerprint(b"body"),
+            reserved_at: 0,
+            response,
+        };
+        let response = StoredResponse {
+            status: 201,
+            body: json!({ "id": 1 }),
+        };
+        let replayed = replay(record(Some(response)), &fingerprint(b"body")).unwrap();
+        assert_eq!((replayed.status, replayed.body), (201, json!({ "id": 1 })));
+        assert!(matches!(
+            replay(record(None), &fingerprint(b"body")),
+            Err(Error::Conflict(_))
+        ));
+        assert!(matches!(
+            replay(record(None), &fingerprint(b"other")),
+            Err(Error::IdempotencyMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn replays_client_errors_but_not_server_errors() {
+        let store: SharedIdempotency = Arc::new(MemoryStore::default());
+        assert_eq!(call(&store, StatusCode::BAD_GATEWAY, "body").await, 502);
+        assert_eq!(call(&store, StatusCode::BAD_REQUEST, "body").await, 400);
+        assert_eq!(call(&store, StatusCode::OK, "body").await, 400);
+
+        let record = store.reserve("key", "other", 0, 0).unwrap().unwrap();
+        assert!(matches!(
+            replay(record, "other"),
+            Err(Error::IdempotencyMismatch)
+        ));
+    }
+
+    #[test]
+    fn frees_stale_reservations() {
+        let store = MemoryStore::default();
+        assert!(store.reserve("key", "first", 100, 0).unwrap().is_none());
+        let record = store.reserve("key", "second", 200, 100).unwrap().unwrap();
+        assert_eq!(record.fingerprint, "first");
+        assert!(matches!(replay(record, "first"), Err(Error::Conflict(_))));
+
+        assert!(store.reserve("key", "second", 300, 101).unwrap().is_none());
+        store
+            .complete(
+                "key",
+                &StoredResponse {
+                    status: 200,
+                    body: json!({}),
+                },
+            )
+            .unwrap();
+        let record = store.reserve("key", "third", i64::MAX, i64::MAX).unwrap();
+        assert_eq!(record.unwrap().fingerprint, "second");
+    }
+}
@@ -1,129 +1,98 @@
-use anyhow::{anyhow, Context, Result};
-use axum::{
-    extract::State,
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{get, post},
-    Json, Router, Server,
-};
-use serde::{Deserialize, Serialize};
-use solana_client::rpc_client::RpcClient;
-use solana_sdk::{pubkey::Pubkey, signature::Keypair, system_transaction};
-use std::{
-    env,
-    net::SocketAddr,
-    str::FromStr,
-    sync::{Arc, RwLock},
-};
-use tracing::{debug, info};
+use anyhow::Result;
+use auth::Auth;
+use axum::{Extension, Router, Server};
+use buy::{jobs::Queue, BuyState};
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Config;
+use session::Sessions;
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use tracing::debug;
+use tracing_subscriber::EnvFilter;
+use webhooks::WebhookState;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct Vendor {
-    wallet_id: String,
-    name: String,
-    #[serde(default)]
-    address: String,
-    #[serde(default)]
-    services: Vec<String>,
-}
-
-#[derive(Clone)]
-struct BuyState {
-    vendors: SharedVendors,
-    client: Arc<RpcClient>,
-}
-
-type SharedVendors = Arc<RwLock<Vec<Vendor>>>;
+mod auth;
+mod buy;
+mod cli;
+mod config;
+mod error;
+mod idempotency;
+mod payment;
+mod purchases;
+mod retry;
+mod session;
+mod store;
+mod vendors;
+mod webhooks;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let vendors = SharedVendors::default();
-    let buy_state = BuyState {
-        client: RpcClient::new("https://api.devnet.solana.com").into(),
-        vendors: vendors.clone(),
-    };
-    let app = Router::new()
-        .route("/vendors", get(list).post(insert).with_state(vendors))
-        .route("/buy", post(buy).with_state(buy_state));
-
-    let port = env::args()
-        .nth(1)
-        .as_deref()
-        .map(u16::from_str)
-        .transpose()
-        .context("failed to parse provided port")?
-        .unwrap_or(3030);
+    // logs go to stderr so commands can print their output to stdout
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(io::stderr)
+        .init();
+    let cli = Cli::parse();
+    let config = Config::load(cli.overrides)?;
+    debug!("using configuration {:?}", config);
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    debug!("listening on {}", addr);
-    Server::bind(&addr).serve(app.into_make_service()).await?;
-
-    Ok(())
-}
-
-struct Error(anyhow::Error);
-
-impl<E: std::error::Error + Send + Sync + 'static> From<E> for Error {
-    fn from(value: E) -> Self {
-        Self(anyhow::Error::new(value))
-    }
-}
-
-impl IntoResponse for Error {
-    fn into_response(self) -> axum::response::Response {
-        #[derive(Serialize)]
-        struct S {
-            error: String,
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config).await,
+        Command::Vendors(command) => cli::vendors(&config, command),
+        Command::CheckRpc => cli::check_rpc(&config).await,
+        Command::GenerateToken => {
+            cli::generate_token();
+            Ok(())
         }
-        let body = Json(S {
-            error: self.0.to_string(),
-        });
-        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct BuyParams {
-    lamports: u64,
-    vendor: String,
-    buyer_pair: String,
-}
-
-#[tracing::instrument(skip(state))]
-async fn buy(State(state): State<BuyState>, Json(params): Json<BuyParams>) -> Result<(), Error> {
-    if !state
-        .vendors
-        .read()
-        .unwrap()
-        .iter()
-        .any(|v| v.wallet_id == params.vendor)
-    {
-        return Err(Error(anyhow!("{} is not whitelisted", params.vendor)));
+async fn serve(config: Config) -> Result<()> {
+    let auth = Auth::new(
+        &config.auth.admin_token_hashes,
+        &config.auth.client_token_hashes,
+        config.auth.insecure_no_auth,
+    )?;
+    let sessions = Arc::new(Sessions::new(
+        Duration::from_secs(config.auth.challenge_ttl_secs),
+        Duration::from_secs(config.auth.session_ttl_secs),
+    ));
+    let stores = store::open(config.storage.kind, &config.storage.path)?;
+    let buy_state = BuyState {
+        client: config.rpc_client().into(),
+        vendors: stores.vendors.clone(),
+        purchases: stores.purchases.clone(),
+        idempotency: stores.idempotency,
+        webhooks: stores.webhooks.clone(),
+        progress: Arc::default(),
+        queue: Queue::new(stores.jobs),
+        confirmation: config.confirmation(),
+    };
+    if config.watcher.interval_secs > 0 {
+        tokio::spawn(buy::watcher::run(
+            buy_state.clone(),
+            Duration::from_secs(config.watcher.interval_secs),
+            Duration::from_secs(config.watcher.expire_after_secs),
+        ));
     }
+    tokio::spawn(buy::jobs::run(buy_state.clone(), config.workers()));
+    tokio::spawn(webhooks::run(stores.webhooks.clone(), config.retries()));
+    let webhook_state = WebhookState {
+        vendors: stores.vendors.clone(),
+        webhooks: stores.webhooks,
+    };
+    let app = Router::new()
+        .merge(vendors::router(stores.vendors))
+        .merge(purchases::router(stores.purchases))
+        .merge(buy::router(buy_state))
+        .merge(webhooks::router(webhook_state))
+        .merge(session::router(sessions.clone()))
+        .layer(Extension(Arc::new(auth)))
+        .layer(Extension(sessions));
 
-    let from_keypair = Keypair::from_base58_string(&params.buyer_pair);
-    let to = Pubkey::from_str(&params.vendor)?;
-    let sig = state
-        .client
-        .send_and_confirm_transaction(&system_transaction::transfer(
-            &from_keypair,
-            &to,
-            params.lamports,
-            state.client.get_latest_blockhash()?,
-        ))?;
-    while !state.client.confirm_transaction(&sig)? {}
-    Ok(())
-}
-
-#[tracing::instrument(skip(vendors))]
-async fn list(State(vendors): State<SharedVendors>) -> Json<Vec<Vendor>> {
-    info!("retrieving all vendors");
-    Json(vendors.read().unwrap().clone())
-}
+    let addr = SocketAddr::new(config.server.host, config.server.port);
+    debug!("listening on {}", addr);
+    Server::bind(&addr).serve(app.into_make_service()).await?;
 
-#[tracing::instrument(skip(vendors))]
-async fn insert(State(vendors): State<SharedVendors>, Json(input): Json<Vendor>) {
-    info!("adding new vendor");
-    vendors.write().unwrap().push(input)
+    Ok(())
 }
@@ -0,0 +1,490 @@
+use crate::error::Error;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{
+    compute_budget,
+    instruction::{CompiledInstruction, Instruction},
+    message::Message,
+    pubkey::Pubkey,
+    system_instruction::{self, SystemInstruction},
+    system_program,
+};
+use spl_associated_token_account::{
+    get_associated_token_address_with_program_id,
+    instruction::create_associated_token_account_idempotent,
+};
+use spl_token_2022::{
+    extension::{transfer_fee::TransferFeeConfig, BaseStateWithExtensions, StateWithExtensions},
+    instruction::TokenInstruction,
+    state,
+};
+
+/// Programs whose tokens can be paid in, the original spl token program and
+/// token-2022, which share their instruction and base account layouts.
+pub const TOKEN_PROGRAMS: [Pubkey; 2] = [spl_token::ID, spl_token_2022::ID];
+
+/// A transfer from a buyer to a vendor, in lamports or, if `mint` is set, in
+/// base units of an spl token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Payment {
+    pub buyer: Pubkey,
+    pub vendor: Pubkey,
+    pub amount: u64,
+    pub mint: Option<Pubkey>,
+}
+
+/// Builds the instructions paying `payment`. Token payments create the
+/// vendor's associated token account, at the buyer's expense, if missing.
+/// `decimals`, if given, must match those of the mint.
+pub async fn instructions(
+    client: &RpcClient,
+    payment: &Payment,
+    decimals: Option<u8>,
+) -> Result<Vec<Instruction>, Error> {
+    let Some(mint) = payment.mint else {
+        return Ok(vec![system_instruction::transfer(
+            &payment.buyer,
+            &payment.vendor,
+            payment.amount,
+        )]);
+    };
+    let Mint {
+        program,
+        decimals: mint_decimals,
+    } = self::mint(client, &mint).await?;
+    if decimals.is_some_and(|decimals| decimals != mint_decimals) {
+        return Err(Error::Validation(format!(
+            "mint {mint} has {mint_decimals} decimals"
+        )));
+    }
+
+    let source = get_associated_token_address_with_program_id(&payment.buyer, &mint, &program);
+    let destination =
+        get_associated_token_address_with_program_id(&payment.vendor, &mint, &program);
+    let mut instructions = Vec::new();
+    if client
+        .get_account_with_commitment(&destination, client.commitment())
+        .await?
+        .value
+        .is_none()
+    {
+        instructions.push(create_associated_token_account_idempotent(
+            &payment.buyer,
+            &payment.vendor,
+            &mint,
+            &program,
+        ));
+    }
+    instructions.push(
+        spl_token_2022::instruction::transfer_checked(
+            &program,
+            &source,
+            &mint,
+            &destination,
+            &payment.buyer,
+            &[],
+            payment.amount,
+            mint_decimals,
+        )
+        .map_err(|e| Error::Internal(e.into()))?,
+    );
+    Ok(instructions)
+}
+
+/// Extracts the payments of a message, rejecting messages containing
+/// anything other than system transfers, spl token `transfer_checked` signed
+/// by the owner of the source account, the creation of associated token
+/// accounts, memos and compute budget requests, the latter two added by
+/// wallets paying Solana Pay requests.
+pub async fn payments(client: &RpcClient, message: &Message) -> Result<Vec<Payment>, Error> {
+    if message.instructions.is_empty() {
+        return Err(Error::Validation(
+            "transaction contains no instructions".into(),
+        ));
+    }
+    let mut payments = Vec::new();
+    for ix in &message.instructions {
+        let account = |i: usize| {
+            ix.accounts
+                .get(i)
+                .and_then(|&index| message.account_keys.get(usize::from(index)))
+                .copied()
+        };
+        let program = message.account_keys.get(usize::from(ix.program_id_index));
+        match program {
+            Some(program) if system_program::check_id(program) => {
+                match (bincode::deserialize(&ix.data), account(0), account(1)) {
+                    (Ok(SystemInstruction::Transfer { lamports }), Some(from), Some(to)) => {
+                        payments.push(Payment {
+                            buyer: from,
+                            vendor: to,
+                            amount: lamports,
+                            mint: None,
+                        })
+                    }
+                    _ => return Err(unsupported()),
+                }
+            }
+            Some(program) if TOKEN_PROGRAMS.contains(program) => {
+                match (
+                    TokenInstruction::unpack(&ix.data),
+                    account(0),
+                    account(1),
+                    account(2),
+                    account(3),
+                ) {
+                    (
+                        Ok(TokenInstruction::TransferChecked { amount, decimals }),
+                        Some(source),
+                        Some(mint),
+                        Some(destination),
+                        Some(authority),
+                    ) => {
+                        let token = self::mint(client, &mint).await?;
+                        if token.program != *program {
+                            return Err(Error::Validation(format!(
+                                "mint {mint} does not belong to token program {program}"
+                            )));
+                        }
+                        if token.decimals != decimals {
+                            return Err(Error::Validation(format!(
+                                "transfer of mint {mint} has wrong decimals"
+                            )));
+                        }
+                        // a delegate could spend from the account of someone else
+                        let buyer = token_owner(client, message, &source, &mint, program).await?;
+                        if buyer != authority {
+                            return Err(Error::Validation(format!(
+                                "transfer from {source} must be signed by its owner {buyer}"
+                            )));
+                        }
+                        let vendor =
+                            token_owner(client, message, &destination, &mint, program).await?;
+                        payments.push(Payment {
+                            buyer,
+                            vendor,
+                            amount,
+                            mint: Some(mint),
+                        })
+                    }
+                    _ => return Err(unsupported()),
+                }
+            }
+            Some(program) if spl_associated_token_account::check_id(program) => {
+                if created_account(message, ix).is_none() {
+                    return Err(unsupported());
+                }
+            }
+            Some(program)
+                if spl_memo::check_id(program)
+                    || spl_memo::v1::check_id(program)
+                    || compute_budget::check_id(program) => {}
+            _ => return Err(unsupported()),
+        }
+    }
+    Ok(payments)
+}
+
+fn unsupported() -> Error {
+    Error::Validation(
+        "transaction may only contain system transfers, spl token transfer_checked, \
+        associated token account creations, memos and compute budget instructions"
+            .into(),
+    )
+}
+
+/// A mint of one of the [`TOKEN_PROGRAMS`].
+#[derive(Debug, Clone, Copy)]
+pub struct Mint {
+    pub program: Pubkey,
+    pub decimals: u8,
+}
+
+/// Looks up a mint, rejecting token-2022 mints charging transfer fees since
+/// vendors would receive less than their price.
+pub async fn mint(client: &RpcClient, mint: &Pubkey) -> Result<Mint, Error> {
+    let not_a_mint = || Error::Validation(format!("{mint} is not an spl token mint"));
+    let account = client
+        .get_account_with_commitment(mint, client.commitment())
+        .await?
+        .value
+        .filter(|account| TOKEN_PROGRAMS.contains(&account.owner))
+        .ok_or_else(not_a_mint)?;
+    let state = StateWithExtensions::<state::Mint>::unpack(&account.data)
+        .map_err(|e| Error::Validation(format!("{mint} is not an spl token mint: {e}")))?;
+    if state.get_extension::<TransferFeeConfig>().is_ok() {
+        return Err(Error::Validation(format!(
+            "mint {mint} charges transfer fees, which is not supported"
+        )));
+    }
+    Ok(Mint {
+        program: account.owner,
+        decimals: state.base.decimals,
+    })
+}
+
+/// Returns the wallet owning a token account of `mint` and `program`, which
+/// may be created by the message itself.
+async fn token_owner(
+    client: &RpcClient,
+    message: &Message,
+    token_account: &Pubkey,
+    mint: &Pubkey,
+    program: &Pubkey,
+) -> Result<Pubkey, Error> {
+    let created = message
+        .instructions
+        .iter()
+        .filter_map(|ix| created_account(message, ix))
+        .find(|created| {
+            created.account == *token_account
+                && created.mint == *mint
+                && created.program == *program
+        });
+    if let Some(created) = created {
+        return Ok(created.wallet);
+    }
+    let account = client
+        .get_account_with_commitment(token_account, client.commitment())
+        .await?
+        .value
+        .filter(|account| account.owner == *program)
+        .and_then(|account| {
+            StateWithExtensions::<state::Account>::unpack(&account.data)
+                .ok()
+                .map(|state| state.base)
+        })
+        .filter(|account| account.mint == *mint)
+        .ok_or_else(|| {
+            Error::Validation(format!(
+                "{token_account} is not a token account of mint {mint}"
+            ))
+        })?;
+    Ok(account.owner)
+}
+
+/// An associated token account created by an instruction.
+struct Created {
+    account: Pubkey,
+    wallet: Pubkey,
+    mint: Pubkey,
+    program: Pubkey,
+}
+
+/// Returns the account an associated token account creation creates, `None`
+/// for any other instruction.
+fn created_account(message: &Message, ix: &CompiledInstruction) -> Option<Created> {
+    let program = message.account_keys.get(usize::from(ix.program_id_index))?;
+    // `Create` is encoded as 0 or as empty data, `CreateIdempotent` as 1
+    if !spl_associated_token_account::check_id(program) || !matches!(ix.data[..], [] | [0] | [1]) {
+        return None;
+    }
+    let account = |i: usize| {
+        ix.accounts
+            .get(i)
+            .and_then(|&index| message.account_keys.get(usize::from(index)))
+            .copied()
+    };
+    let created = Created {
+        account: account(1)?,
+        wallet: account(2)?,
+        mint: account(3)?,
+        program: account(5)?,
+    };
+    (TOKEN_PROGRAMS.contains(&created.program)
+        && get_associated_token_address_with_program_id(
+            &created.wallet,
+            &created.mint,
+            &created.program,
+        ) == created.account)
+        .then_some(created)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, routing::post, Json, Router, Server};
+    use serde_json::{json, Value};
+    use solana_sdk::{
+        account::Account, compute_budget::ComputeBudgetInstruction, program_pack::Pack,
+    };
+    use spl_associated_token_account::instruction::create_associated_token_account;
+    use std::{collections::HashMap, sync::Arc};
+
+    /// An rpc node answering `getAccountInfo` from `accounts`, and the
+    /// `getVersion` the client asks first.
+    async fn rpc(accounts: HashMap<Pubkey, Account>) -> RpcClient {
+        async fn handle(
+            State(accounts): State<Arc<HashMap<Pubkey, Account>>>,
+            Json(request): Json<Value>,
+        ) -> Json<Value> {
+            let result = match request["method"].as_str() {
+                Some("getVersion") => json!({ "solana-core": "1.15.2" }),
+                Some("getAccountInfo") => {
+                    let pubkey: Pubkey = request["params"][0].as_str().unwrap().parse().unwrap();
+                    let value = accounts.get(&pubkey).map(|account| {
+                        json!({
+                            "lamports": account.lamports,
+                            "data": [base64::encode(&account.data), "base64"],
+                            "owner": account.owner.to_string(),
+                            "executable": false,
+                            "rentEpoch": 0,
+                        })
+                    });
+                    json!({ "context": { "slot": 1 }, "value": value })
+                }
+                method => panic!("unexpected rpc method {method:?}"),
+            };
+            Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+        }
+        let router = Router::new()
+            .route("/", post(handle))
+            .with_state(Arc::new(accounts));
+        let server =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(router.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        RpcClient::new(url)
+    }
+
+    fn account<T: Pack>(owner: Pubkey, state: T) -> Account {
+        let mut data = vec![0; T::LEN];
+        state.pack_into_slice(&mut data);
+        Account {
+            lamports: 1,
+            data,
+            owner,
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
+    fn mint_account(program: Pubkey) -> Account {
+        account(
+            program,
+            state::Mint {
+                decimals: 6,
+                is_initialized: true,
+                ..state::Mint::default()
+            },
+        )
+    }
+
+    fn token_account(program: Pubkey, mint: Pubkey, owner: Pubkey) -> Account {
+        account(
+            program,
+            state::Account {
+                mint,
+                owner,
+                amount: 1_000_000,
+                state: state::AccountState::Initialized,
+                ..state::Account::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn pays_in_tokens_of_either_program() {
+        for program in TOKEN_PROGRAMS {
+            let (buyer, vendor, mint) = (
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+            );
+            let source = get_associated_token_address_with_program_id(&buyer, &mint, &program);
+            let client = rpc(HashMap::from([
+                (mint, mint_account(program)),
+                (source, token_account(program, mint, buyer)),
+            ]))
+            .await;
+            let payment = Payment {
+                buyer,
+                vendor,
+                amount: 5000,
+                mint: Some(mint),
+            };
+            let instructions = instructions(&client, &payment, Some(6)).await.unwrap();
+            // the vendor's account does not exist yet
+            assert_eq!(instructions.len(), 2);
+            assert!(instructions.iter().any(|ix| ix.program_id == program));
+            let message = Message::new(&instructions, Some(&buyer));
+            assert_eq!(payments(&client, &message).await.unwrap(), [payment]);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_transfers_not_signed_by_the_source_owner() {
+        let program = spl_token_2022::ID;
+        let (buyer, owner, vendor, mint) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let source = get_associated_token_address_with_program_id(&owner, &mint, &program);
+        let destination = get_associated_token_address_with_program_id(&vendor, &mint, &program);
+        let client = rpc(HashMap::from([
+            (mint, mint_account(program)),
+            (source, token_account(program, mint, owner)),
+            (destination, token_account(program, mint, vendor)),
+        ]))
+        .await;
+        // `buyer` would spend as a delegate of `owner`
+        let transfer = spl_token_2022::instruction::transfer_checked(
+            &program,
+            &source,
+            &mint,
+            &destination,
+            &buyer,
+            &[],
+            5000,
+            6,
+        )
+        .unwrap();
+        let message = Message::new(&[transfer], Some(&buyer));
+        assert!(matches!(
+            payments(&client, &message).await,
+            Err(Error::Validation(e)) if e.contains("must be signed by its owner")
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_system_transfers_and_refuses_other_instructions() {
+        // nothing is looked up without token transfers
+        let client = RpcClient::new("http://127.0.0.1:1".into());
+        let (buyer, vendor) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let message = Message::new(
+            &[
+                ComputeBudgetInstruction::set_compute_unit_limit(10_000),
+                system_instruction::transfer(&buyer, &vendor, 5000),
+                spl_memo::build_memo(b"order 1", &[&buyer]),
+            ],
+            Some(&buyer),
+        );
+        let payment = Payment {
+            buyer,
+            vendor,
+            amount: 5000,
+            mint: None,
+        };
+        assert_eq!(payments(&client, &message).await.unwrap(), [payment]);
+
+        let mint = Pubkey::new_unique();
+        for ix in [
+            system_instruction::allocate(&buyer, 10),
+            // unchecked transfers do not tell the mint
+            spl_token::instruction::transfer(&spl_token::ID, &buyer, &vendor, &buyer, &[], 5000)
+                .unwrap(),
+            create_associated_token_account(&buyer, &vendor, &mint, &system_program::ID),
+            Instruction::new_with_bytes(Pubkey::new_unique(), &[], vec![]),
+        ] {
+            let message = Message::new(&[ix], Some(&buyer));
+            assert!(matches!(
+                payments(&client, &message).await,
+                Err(Error::Validation(e)) if e.starts_with("transaction may only contain")
+            ));
+        }
+        assert!(payments(&client, &Message::new(&[], Some(&buyer)))
+            .await
+            .is_err());
+    }
+}
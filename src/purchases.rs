@@ -0,0 +1,226 @@
+use crate::{auth::Admin, error::Error, store::PurchaseStore};
+use axum::{
+    extract::{Query, State},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use solana_sdk::clock::Slot;
+use solana_transaction_status::TransactionConfirmationStatus;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::info;
+
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PurchaseStatus {
+    Pending,
+    Confirmed,
+    Failed,
+}
+
+impl PurchaseStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Confirmed => "confirmed",
+            Self::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(Self::Pending),
+            "confirmed" => Some(Self::Confirmed),
+            "failed" => Some(Self::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// Ledger entry recorded for every line item of a submitted order, rejected
+/// ones included.
+#[derive(Debug, Clone, Serialize)]
+pub struct Purchase {
+    pub id: u64,
+    /// Absent for purchases made before orders.
+    pub order_id: Option<u64>,
+    /// Unix timestamp, in seconds, of the `/buy` or `/orders` request.
+    pub created_at: i64,
+    pub buyer: String,
+    pub vendor: String,
+    /// Id of the purchased service, absent for purchases made before the catalog.
+    pub service: Option<String>,
+    pub quantity: u32,
+    /// Total of the line, in lamports, or in base units of `mint`.
+    pub amount: u64,
+    /// `amount` of native sol lines, under its name from before token
+    /// payments for older clients, absent otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lamports: Option<u64>,
+    /// Absent for native sol.
+    pub mint: Option<String>,
+    pub signature: String,
+    pub status: PurchaseStatus,
+    pub error: Option<String>,
+}
+
+impl Purchase {
+    /// A line item of an order, the order fills in the rest.
+    pub fn item(
+        vendor: String,
+        service: String,
+        quantity: u32,
+        amount: u64,
+        mint: Option<String>,
+    ) -> Self {
+        Self {
+            id: 0,
+            order_id: None,
+            created_at: 0,
+            buyer: String::new(),
+            vendor,
+            service: Some(service),
+            quantity,
+            amount,
+            lamports: mint.is_none().then_some(amount),
+            mint,
+            signature: String::new(),
+            status: PurchaseStatus::Pending,
+            error: None,
+        }
+    }
+}
+
+/// Line items paid by a single transaction, their status is the order's.
+#[derive(Debug, Clone, Serialize)]
+pub struct Order {
+    pub id: u64,
+    pub created_at: i64,
+    /// Empty, as is `signature`, until a Solana Pay request is paid.
+    pub buyer: String,
+    pub signature: String,
+    pub status: PurchaseStatus,
+    pub error: Option<String>,
+    #[serde(flatten)]
+    pub receipt: Receipt,
+    /// Base58 pubkey identifying the transaction paying a Solana Pay request.
+    pub reference: Option<String>,
+    pub memo: Option<String>,
+    /// Solana Pay transfer request url.
+    pub url: Option<String>,
+    pub items: Vec<Purchase>,
+}
+
+impl Order {
+    /// A failed order recording a purchase attempt rejected before it could
+    /// be submitted.
+    pub fn rejected(buyer: String, signature: String, items: Vec<Purchase>, error: String) -> Self {
+        let items = items
+            .into_iter()
+            .map(|item| Purchase {
+                status: PurchaseStatus::Failed,
+                error: Some(error.clone()),
+                ..item
+            })
+            .collect();
+        Self {
+            status: PurchaseStatus::Failed,
+            error: Some(error),
+            ..Self::pending(buyer, signature, items)
+        }
+    }
+
+    pub fn pending(buyer: String, signature: String, items: Vec<Purchase>) -> Self {
+        let created_at = now();
+        let items = items
+            .into_iter()
+            .map(|item| Purchase {
+                created_at,
+                buyer: buyer.clone(),
+                signature: signature.clone(),
+                ..item
+            })
+            .collect();
+        Self {
+            id: 0,
+            created_at,
+            buyer,
+            signature,
+            status: PurchaseStatus::Pending,
+            error: None,
+            receipt: Receipt::default(),
+            reference: None,
+            memo: None,
+            url: None,
+            items,
+        }
+    }
+}
+
+/// Where the transaction paying an order landed, known once it is confirmed.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Receipt {
+    /// Highest commitment the transaction reached.
+    pub commitment: Option<TransactionConfirmationStatus>,
+    pub slot: Option<Slot>,
+    /// Fee paid by the buyer, in lamports.
+    pub fee: Option<u64>,
+}
+
+/// Query of `GET /purchases`, time bounds are unix timestamps in seconds.
+#[derive(Debug, Default, Deserialize)]
+pub struct PurchaseFilter {
+    pub vendor: Option<String>,
+    pub buyer: Option<String>,
+    /// Inclusive lower bound on `created_at`.
+    pub since: Option<i64>,
+    /// Exclusive upper bound on `created_at`.
+    pub until: Option<i64>,
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: u32,
+}
+
+impl PurchaseFilter {
+    pub fn limit(&self) -> u32 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT)
+    }
+
+    pub fn matches(&self, purchase: &Purchase) -> bool {
+        self.vendor.as_ref().is_none_or(|v| *v == purchase.vendor)
+            && self.buyer.as_ref().is_none_or(|b| *b == purchase.buyer)
+            && self.since.is_none_or(|since| purchase.created_at >= since)
+            && self.until.is_none_or(|until| purchase.created_at < until)
+    }
+}
+
+pub type SharedPurchases = Arc<dyn PurchaseStore>;
+
+pub fn router(purchases: SharedPurchases) -> Router {
+    Router::new()
+        .route("/purchases", get(list))
+        .with_state(purchases)
+}
+
+/// Lists purchases, newest first.
+#[tracing::instrument(skip(purchases))]
+async fn list(
+    _: Admin,
+    State(purchases): State<SharedPurchases>,
+    Query(filter): Query<PurchaseFilter>,
+) -> Result<Json<Vec<Purchase>>, Error> {
+    info!("retrieving purchases");
+    Ok(Json(purchases.list(&filter)?))
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
@@ -0,0 +1,26 @@
+use std::time::Duration;
+
+/// Delay before the attempt following the `attempts`th failed one: `initial`,
+/// doubled after every further failed attempt, up to `max`.
+pub fn backoff(initial: Duration, max: Duration, attempts: u32) -> Duration {
+    initial
+        .saturating_mul(1 << attempts.saturating_sub(1).min(20))
+        .min(max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_the_delay_up_to_the_maximum() {
+        let delay = |attempts| backoff(Duration::from_secs(2), Duration::from_secs(60), attempts);
+        let delays: Vec<_> = (0..=7).map(|attempts| delay(attempts).as_secs()).collect();
+        assert_eq!(delays, [2, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(delay(u32::MAX), Duration::from_secs(60));
+        assert_eq!(
+            backoff(Duration::from_secs(u64::MAX / 2), Duration::MAX, 10),
+            Duration::MAX
+        );
+    }
+}
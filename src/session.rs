@@ -0,0 +1,278 @@
+use crate::{
+    auth::{self, Client, SharedAuth},
+    error::Error,
+};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, State},
+    http::request::Parts,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+/// Challenges pending at most, the ones closest to expiring make room for new
+/// ones beyond it.
+const MAX_CHALLENGES: usize = 10_000;
+
+/// Nonces handed out by `GET /auth/challenge` and the sessions of the buyers
+/// who signed them. Both are kept in memory only, a restart logs everyone out.
+pub struct Sessions {
+    challenge_ttl: Duration,
+    session_ttl: Duration,
+    /// Nonce to expiry.
+    challenges: Mutex<HashMap<String, Instant>>,
+    /// Hash of the session token to session.
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Session {
+    buyer: Pubkey,
+    expires: Instant,
+}
+
+pub type SharedSessions = Arc<Sessions>;
+
+impl Sessions {
+    pub fn new(challenge_ttl: Duration, session_ttl: Duration) -> Self {
+        Self {
+            challenge_ttl,
+            session_ttl,
+            challenges: Mutex::default(),
+            sessions: Mutex::default(),
+        }
+    }
+
+    fn challenge(&self) -> String {
+        let nonce = auth::random_token();
+        let now = Instant::now();
+        let mut challenges = self.challenges.lock().unwrap();
+        challenges.retain(|_, expires| *expires > now);
+        if challenges.len() >= MAX_CHALLENGES {
+            let oldest = challenges
+                .iter()
+                .min_by_key(|(_, expires)| **expires)
+                .map(|(nonce, _)| nonce.clone());
+            if let Some(oldest) = oldest {
+                challenges.remove(&oldest);
+            }
+        }
+        challenges.insert(nonce.clone(), now + self.challenge_ttl);
+        nonce
+    }
+
+    /// Consumes the nonce, so a signed challenge can only be used once.
+    fn take_challenge(&self, nonce: &str) -> bool {
+        let expires = self.challenges.lock().unwrap().remove(nonce);
+        expires.is_some_and(|expires| expires > Instant::now())
+    }
+
+    fn open(&self, buyer: Pubkey) -> String {
+        let token = auth::random_token();
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(
+            auth::hash(&token),
+            Session {
+                buyer,
+                expires: now + self.session_ttl,
+            },
+        );
+        token
+    }
+
+    fn buyer(&self, token: &str) -> Option<Pubkey> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(&auth::hash(token))
+            .filter(|session| session.expires > Instant::now())
+            .map(|session| session.buyer)
+    }
+}
+
+/// The text buyers sign with their wallet, the nonce makes it single use.
+fn message(nonce: &str) -> String {
+    format!("Sign in to purchase from whitelisted vendors.\n\nNonce: {nonce}")
+}
+
+pub fn router(sessions: SharedSessions) -> Router {
+    Router::new()
+        .route("/auth/challenge", get(challenge))
+        .route("/auth/verify", post(verify))
+        .with_state(sessions)
+}
+
+#[derive(Debug, Serialize)]
+struct Challenge {
+    nonce: String,
+    /// To be signed, as utf-8 bytes, by the buyer's wallet.
+    message: String,
+    expires_in: u64,
+}
+
+#[tracing::instrument(skip(sessions))]
+async fn challenge(_: Client, State(sessions): State<SharedSessions>) -> Json<Challenge> {
+    info!("issuing challenge");
+    let nonce = sessions.challenge();
+    Json(Challenge {
+        message: message(&nonce),
+        nonce,
+        expires_in: sessions.challenge_ttl.as_secs(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct VerifyParams {
+    pubkey: String,
+    nonce: String,
+    /// Base58 encoded ed25519 signature of the challenge message.
+    signature: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SessionToken {
+    token: String,
+    pubkey: String,
+    expires_in: u64,
+}
+
+/// Opens a session for the buyer who signed a challenge.
+#[tracing::instrument(skip(sessions))]
+async fn verify(
+    _: Client,
+    State(sessions): State<SharedSessions>,
+    Json(params): Json<VerifyParams>,
+) -> Result<Json<SessionToken>, Error> {
+    info!("verifying challenge");
+    let pubkey = Pubkey::from_str(&params.pubkey)
+        .map_err(|e| Error::Validation(format!("invalid pubkey {}: {e}", params.pubkey)))?;
+    let signature = Signature::from_str(&params.signature)
+        .map_err(|e| Error::Validation(format!("invalid signature: {e}")))?;
+    if !sessions.take_challenge(&params.nonce) {
+        return Err(Error::Unauthorized(
+            "unknown or expired challenge nonce".into(),
+        ));
+    }
+    if !signature.verify(pubkey.as_ref(), message(&params.nonce).as_bytes()) {
+        return Err(Error::Unauthorized(
+            "signature does not match the pubkey".into(),
+        ));
+    }
+    Ok(Json(SessionToken {
+        token: sessions.open(pubkey),
+        pubkey: pubkey.to_string(),
+        expires_in: sessions.session_ttl.as_secs(),
+    }))
+}
+
+/// Extractor of who a purchase is made for, from the `Authorization: Bearer`
+/// header: a buyer session token or, as before sessions existed, an api token.
+#[derive(Debug, Clone, Copy)]
+pub enum Buyer {
+    /// Signed in with a wallet, may only purchase as itself.
+    Session(Pubkey),
+    /// Authenticated with an api token, purchasing for whoever signs.
+    Token,
+}
+
+impl Buyer {
+    /// The buyer a request acts for: the signed in one, who `claimed` must
+    /// be if given, or `claimed` when authenticated with an api token.
+    pub fn resolve(self, claimed: Option<&Pubkey>) -> Result<Pubkey, Error> {
+        match (self, claimed) {
+            (Self::Session(signed_in), Some(claimed)) if signed_in != *claimed => {
+                Err(Error::Forbidden(format!(
+                    "signed in as {signed_in}, can not purchase as {claimed}"
+                )))
+            }
+            (Self::Session(signed_in), _) => Ok(signed_in),
+            (Self::Token, Some(claimed)) => Ok(*claimed),
+            (Self::Token, None) => Err(Error::Validation(
+                "buyer is required without a session token".into(),
+            )),
+        }
+    }
+
+    /// Whether purchases made by `buyer` are visible to this request.
+    pub fn owns(self, buyer: &str) -> bool {
+        match self {
+            Self::Session(signed_in) => signed_in.to_string() == buyer,
+            Self::Token => true,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Buyer {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Error> {
+        let sessions = parts
+            .extensions
+            .get::<SharedSessions>()
+            .expect("sessions extension is not installed");
+        let token = auth::bearer(&parts.headers)
+            .ok_or_else(|| Error::Unauthorized("missing session token".into()))?;
+        if let Some(buyer) = sessions.buyer(token) {
+            return Ok(Self::Session(buyer));
+        }
+        let auth = parts
+            .extensions
+            .get::<SharedAuth>()
+            .expect("auth extension is not installed");
+        auth.role(token)
+            .map(|_| Self::Token)
+            .ok_or_else(|| Error::Unauthorized("invalid or expired session token".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_the_buyer_of_a_session_or_token() {
+        let signed_in = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let session = Buyer::Session(signed_in);
+        assert_eq!(session.resolve(None).unwrap(), signed_in);
+        assert_eq!(session.resolve(Some(&signed_in)).unwrap(), signed_in);
+        assert!(matches!(
+            session.resolve(Some(&other)),
+            Err(Error::Forbidden(_))
+        ));
+        assert!(session.owns(&signed_in.to_string()));
+        assert!(!session.owns(&other.to_string()));
+
+        assert_eq!(Buyer::Token.resolve(Some(&other)).unwrap(), other);
+        assert!(matches!(
+            Buyer::Token.resolve(None),
+            Err(Error::Validation(_))
+        ));
+        assert!(Buyer::Token.owns(&other.to_string()));
+    }
+
+    #[test]
+    fn caps_pending_challenges() {
+        let sessions = Sessions::new(Duration::from_secs(60), Duration::from_secs(60));
+        let first = sessions.challenge();
+        for _ in 0..MAX_CHALLENGES {
+            sessions.challenge();
+        }
+        assert_eq!(sessions.challenges.lock().unwrap().len(), MAX_CHALLENGES);
+        assert!(!sessions.take_challenge(&first));
+        let last = sessions.challenge();
+        assert!(sessions.take_challenge(&last));
+        assert!(!sessions.take_challenge(&last));
+    }
+}
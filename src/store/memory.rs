@@ -0,0 +1,448 @@
+use super::{IdempotencyStore, JobStore, PurchaseStore, VendorStore, WebhookStore};
+use crate::{
+    buy::jobs::{Job, JobStatus},
+    idempotency::{IdempotencyRecord, StoredResponse},
+    purchases::{Order, Purchase, PurchaseFilter, PurchaseStatus, Receipt},
+    vendors::{Vendor, VendorStatus},
+    webhooks::{Delivery, DeliveryFilter, DeliveryStatus, Webhook},
+};
+use anyhow::Result;
+use std::{collections::HashMap, sync::RwLock};
+
+#[derive(Default)]
+pub struct MemoryStore {
+    vendors: RwLock<Vec<Vendor>>,
+    purchases: RwLock<Vec<Purchase>>,
+    /// Without their items, which are in `purchases`.
+    orders: RwLock<Vec<Order>>,
+    idempotency: RwLock<HashMap<String, IdempotencyRecord>>,
+    webhooks: RwLock<HashMap<String, Webhook>>,
+    deliveries: RwLock<Vec<Delivery>>,
+    jobs: RwLock<Vec<Job>>,
+}
+
+impl VendorStore for MemoryStore {
+    fn get(&self, wallet_id: &str) -> Result<Option<Vendor>> {
+        Ok(self
+            .vendors
+            .read()
+            .unwrap()
+            .iter()
+            .find(|v| v.wallet_id == wallet_id)
+            .cloned())
+    }
+
+    fn list(&self) -> Result<Vec<Vendor>> {
+        Ok(self.vendors.read().unwrap().clone())
+    }
+
+    fn insert(&self, vendor: &Vendor) -> Result<bool> {
+        let mut vendors = self.vendors.write().unwrap();
+        if vendors.iter().any(|v| v.wallet_id == vendor.wallet_id) {
+            return Ok(false);
+        }
+        vendors.push(vendor.clone());
+        Ok(true)
+    }
+
+    fn insert_all(&self, new: &[Vendor]) -> Result<Vec<String>> {
+        let mut vendors = self.vendors.write().unwrap();
+        let existing: Vec<_> = new
+            .iter()
+            .filter(|n| vendors.iter().any(|v| v.wallet_id == n.wallet_id))
+            .map(|n| n.wallet_id.clone())
+            .collect();
+        if existing.is_empty() {
+            vendors.extend_from_slice(new);
+        }
+        Ok(existing)
+    }
+
+    fn update(&self, vendor: &Vendor) -> Result<bool> {
+        let mut vendors = self.vendors.write().unwrap();
+        match vendors.iter_mut().find(|v| v.wallet_id == vendor.wallet_id) {
+            Some(existing) => {
+                *existing = Vendor {
+                    status: existing.status,
+                    ..vendor.clone()
+                };
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn reapply(&self, vendor: &Vendor) -> Result<bool> {
+        let mut vendors = self.vendors.write().unwrap();
+        match vendors
+            .iter_mut()
+            .find(|v| v.wallet_id == vendor.wallet_id && v.status == VendorStatus::Rejected)
+        {
+            Some(existing) => {
+                *existing = Vendor {
+                    status: VendorStatus::Pending,
+                    ..vendor.clone()
+                };
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn set_status(&self, wallet_id: &str, status: VendorStatus) -> Result<bool> {
+        let mut vendors = self.vendors.write().unwrap();
+        match vendors.iter_mut().find(|v| v.wallet_id == wallet_id) {
+            Some(existing) => {
+                existing.status = status;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn delete(&self, wallet_id: &str) -> Result<bool> {
+        let mut vendors = self.vendors.write().unwrap();
+        let len = vendors.len();
+        vendors.retain(|v| v.wallet_id != wallet_id);
+        Ok(vendors.len() != len)
+    }
+}
+
+impl MemoryStore {
+    fn with_items(&self, order: &Order) -> Order {
+        let items = self
+            .purchases
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|p| p.order_id == Some(order.id))
+            .cloned()
+            .collect();
+        Order {
+            items,
+            ..order.clone()
+        }
+    }
+}
+
+impl PurchaseStore for MemoryStore {
+    fn insert_order(&self, order: &Order) -> Result<Option<u64>> {
+        let mut orders = self.orders.write().unwrap();
+        let mut purchases = self.purchases.write().unwrap();
+        if order.status != PurchaseStatus::Failed && pays_another(&orders, 0, &order.signature) {
+            return Ok(None);
+        }
+        let id = orders.len() as u64 + 1;
+        for item in &order.items {
+            let item_id = purchases.len() as u64 + 1;
+            purchases.push(Purchase {
+                id: item_id,
+                order_id: Some(id),
+                ..item.clone()
+            });
+        }
+        orders.push(Order {
+            id,
+            items: Vec::new(),
+            ..order.clone()
+        });
+        Ok(Some(id))
+    }
+
+    fn get_order(&self, id: u64) -> Result<Option<Order>> {
+        let orders = self.orders.read().unwrap();
+        Ok(orders
+            .iter()
+            .find(|o| o.id == id)
+            .map(|order| self.with_items(order)))
+    }
+
+    fn find_order(&self, reference: &str) -> Result<Option<Order>> {
+        let orders = self.orders.read().unwrap();
+        Ok(orders
+            .iter()
+            .find(|o| o.reference.as_deref() == Some(reference))
+            .map(|order| self.with_items(order)))
+    }
+
+    fn find_order_by_signature(&self, signature: &str) -> Result<Option<Order>> {
+        let orders = self.orders.read().unwrap();
+        Ok(orders
+            .iter()
+            .rev()
+            .filter(|o| !signature.is_empty() && o.signature == signature)
+            .min_by_key(|o| o.status == PurchaseStatus::Failed)
+            .map(|order| self.with_items(order)))
+    }
+
+    fn set_order_status(
+        &self,
+        id: u64,
+        status: PurchaseStatus,
+        error: Option<&str>,
+    ) -> Result<bool> {
+        let mut orders = self.orders.write().unwrap();
+        let mut purchases = self.purchases.write().unwrap();
+        let Some(order) = orders
+            .iter_mut()
+            .find(|o| o.id == id && o.status == PurchaseStatus::Pending)
+        else {
+            return Ok(false);
+        };
+        order.status = status;
+        order.error = error.map(Into::into);
+        for purchase in purchases.iter_mut().filter(|p| p.order_id == Some(id)) {
+            purchase.status = status;
+            purchase.error = error.map(Into::into);
+        }
+        Ok(true)
+    }
+
+    fn set_order_receipt(&self, id: u64, receipt: &Receipt) -> Result<()> {
+        let mut orders = self.orders.write().unwrap();
+        if let Some(order) = orders.iter_mut().find(|o| o.id == id) {
+            order.receipt = Receipt {
+                fee: receipt.fee.or(order.receipt.fee),
+                ..receipt.clone()
+            };
+        }
+        Ok(())
+    }
+
+    fn pending_orders(&self) -> Result<Vec<Order>> {
+        let orders = self.orders.read().unwrap();
+        Ok(orders
+            .iter()
+            .filter(|o| o.status == PurchaseStatus::Pending)
+            .map(|order| self.with_items(order))
+            .collect())
+    }
+
+    fn confirm_order(&self, id: u64, buyer: &str, signature: &str) -> Result<bool> {
+        let mut orders = self.orders.write().unwrap();
+        let mut purchases = self.purchases.write().unwrap();
+        if pays_another(&orders, id, signature) {
+            return Ok(false);
+        }
+        let Some(order) = orders
+            .iter_mut()
+            .find(|o| o.id == id && o.status == PurchaseStatus::Pending)
+        else {
+            return Ok(false);
+        };
+        order.buyer = buyer.into();
+        order.signature = signature.into();
+        order.status = PurchaseStatus::Confirmed;
+        order.error = None;
+        for purchase in purchases.iter_mut().filter(|p| p.order_id == Some(id)) {
+            purchase.buyer = buyer.into();
+            purchase.signature = signature.into();
+            purchase.status = PurchaseStatus::Confirmed;
+            purchase.error = None;
+        }
+        Ok(true)
+    }
+
+    fn list(&self, filter: &PurchaseFilter) -> Result<Vec<Purchase>> {
+        Ok(self
+            .purchases
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|p| filter.matches(p))
+            .skip(filter.offset as usize)
+            .take(filter.limit() as usize)
+            .cloned()
+            .collect())
+    }
+}
+
+/// Whether a transaction pays an order other than `id` which did not fail.
+fn pays_another(orders: &[Order], id: u64, signature: &str) -> bool {
+    !signature.is_empty()
+        && orders
+            .iter()
+            .any(|o| o.id != id && o.signature == signature && o.status != PurchaseStatus::Failed)
+}
+
+impl IdempotencyStore for MemoryStore {
+    fn reserve(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        now: i64,
+        stale_before: i64,
+    ) -> Result<Option<IdempotencyRecord>> {
+        let mut records = self.idempotency.write().unwrap();
+        if let Some(record) = records.get(key) {
+            if record.response.is_some() || record.reserved_at >= stale_before {
+                return Ok(Some(record.clone()));
+            }
+        }
+        records.insert(
+            key.into(),
+            IdempotencyRecord {
+                fingerprint: fingerprint.into(),
+                reserved_at: now,
+                response: None,
+            },
+        );
+        Ok(None)
+    }
+
+    fn complete(&self, key: &str, response: &StoredResponse) -> Result<()> {
+        if let Some(record) = self.idempotency.write().unwrap().get_mut(key) {
+            record.response.get_or_insert_with(|| response.clone());
+        }
+        Ok(())
+    }
+
+    fn release(&self, key: &str) -> Result<()> {
+        let mut records = self.idempotency.write().unwrap();
+        if records
+            .get(key)
+            .is_some_and(|record| record.response.is_none())
+        {
+            records.remove(key);
+        }
+        Ok(())
+    }
+}
+
+impl WebhookStore for MemoryStore {
+    fn get_webhook(&self, vendor: &str) -> Result<Option<Webhook>> {
+        Ok(self.webhooks.read().unwrap().get(vendor).cloned())
+    }
+
+    fn set_webhook(&self, webhook: &Webhook) -> Result<()> {
+        self.webhooks
+            .write()
+            .unwrap()
+            .insert(webhook.vendor.clone(), webhook.clone());
+        Ok(())
+    }
+
+    fn delete_webhook(&self, vendor: &str) -> Result<bool> {
+        Ok(self.webhooks.write().unwrap().remove(vendor).is_some())
+    }
+
+    fn insert_delivery(&self, delivery: &Delivery) -> Result<u64> {
+        let mut deliveries = self.deliveries.write().unwrap();
+        let id = deliveries.len() as u64 + 1;
+        deliveries.push(Delivery {
+            id,
+            ..delivery.clone()
+        });
+        Ok(id)
+    }
+
+    fn get_delivery(&self, id: u64) -> Result<Option<Delivery>> {
+        Ok(self
+            .deliveries
+            .read()
+            .unwrap()
+            .iter()
+            .find(|d| d.id == id)
+            .cloned())
+    }
+
+    fn due_deliveries(&self, now: i64, per_vendor: u32, limit: u32) -> Result<Vec<Delivery>> {
+        let mut counts = HashMap::new();
+        Ok(self
+            .deliveries
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|d| d.status == DeliveryStatus::Pending && d.next_attempt_at <= now)
+            .filter(|d| {
+                let count = counts.entry(&d.vendor).or_insert(0);
+                *count += 1;
+                *count <= per_vendor
+            })
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn update_delivery(&self, delivery: &Delivery) -> Result<()> {
+        let mut deliveries = self.deliveries.write().unwrap();
+        if let Some(existing) = deliveries.iter_mut().find(|d| d.id == delivery.id) {
+            *existing = delivery.clone();
+        }
+        Ok(())
+    }
+
+    fn list_deliveries(&self, filter: &DeliveryFilter) -> Result<Vec<Delivery>> {
+        Ok(self
+            .deliveries
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|d| filter.matches(d))
+            .skip(filter.offset as usize)
+            .take(filter.limit() as usize)
+            .cloned()
+            .collect())
+    }
+}
+
+impl JobStore for MemoryStore {
+    fn insert_job(&self, job: &Job) -> Result<u64> {
+        let mut jobs = self.jobs.write().unwrap();
+        let id = jobs.len() as u64 + 1;
+        jobs.push(Job { id, ..job.clone() });
+        Ok(id)
+    }
+
+    fn get_job(&self, id: u64) -> Result<Option<Job>> {
+        Ok(self
+            .jobs
+            .read()
+            .unwrap()
+            .iter()
+            .find(|j| j.id == id)
+            .cloned())
+    }
+
+    fn find_job_by_signature(&self, signature: &str) -> Result<Option<Job>> {
+        Ok(self
+            .jobs
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|j| j.signature == signature)
+            .cloned())
+    }
+
+    fn claim_job(&self, now: i64) -> Result<Option<Job>> {
+        let mut jobs = self.jobs.write().unwrap();
+        let job = jobs
+            .iter_mut()
+            .find(|j| j.status == JobStatus::Queued && j.next_attempt_at <= now);
+        Ok(job.map(|job| {
+            job.status = JobStatus::Running;
+            job.clone()
+        }))
+    }
+
+    fn update_job(&self, job: &Job) -> Result<()> {
+        let mut jobs = self.jobs.write().unwrap();
+        if let Some(existing) = jobs.iter_mut().find(|j| j.id == job.id) {
+            *existing = job.clone();
+        }
+        Ok(())
+    }
+
+    fn requeue_running_jobs(&self) -> Result<usize> {
+        let mut jobs = self.jobs.write().unwrap();
+        let mut count = 0;
+        for job in jobs.iter_mut().filter(|j| j.status == JobStatus::Running) {
+            job.status = JobStatus::Queued;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
@@ -0,0 +1,321 @@
+use crate::{
+    buy::jobs::Job,
+    config::StoreKind,
+    idempotency::{IdempotencyRecord, StoredResponse},
+    purchases::{Order, Purchase, PurchaseFilter, PurchaseStatus, Receipt},
+    vendors::{Vendor, VendorStatus},
+    webhooks::{Delivery, DeliveryFilter, Webhook},
+};
+use anyhow::Result;
+use std::{path::Path, sync::Arc};
+
+mod memory;
+mod sqlite;
+
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+pub trait VendorStore: Send + Sync {
+    fn get(&self, wallet_id: &str) -> Result<Option<Vendor>>;
+    fn list(&self) -> Result<Vec<Vendor>>;
+    /// Returns `false` if a vendor with this `wallet_id` already exists.
+    fn insert(&self, vendor: &Vendor) -> Result<bool>;
+    /// Inserts every vendor or, if some of them already exist, none of them.
+    /// Returns the wallet ids which already exist.
+    fn insert_all(&self, vendors: &[Vendor]) -> Result<Vec<String>>;
+    /// Updates every field but the status. Returns `false` if no vendor with
+    /// this `wallet_id` exists.
+    fn update(&self, vendor: &Vendor) -> Result<bool>;
+    /// Replaces a rejected vendor with a pending application, in one write.
+    /// Returns `false` if no rejected vendor with this `wallet_id` exists.
+    fn reapply(&self, vendor: &Vendor) -> Result<bool>;
+    /// Returns `false` if no vendor with this `wallet_id` exists.
+    fn set_status(&self, wallet_id: &str, status: VendorStatus) -> Result<bool>;
+    /// Returns `false` if no vendor with this `wallet_id` exists.
+    fn delete(&self, wallet_id: &str) -> Result<bool>;
+}
+
+pub trait PurchaseStore: Send + Sync {
+    /// Records an order and every one of its items, or nothing if any fails,
+    /// and returns the order id. The ids of the order and items are ignored.
+    /// Returns `None` if its signature already pays another order which did
+    /// not fail, unless this one failed.
+    fn insert_order(&self, order: &Order) -> Result<Option<u64>>;
+    fn get_order(&self, id: u64) -> Result<Option<Order>>;
+    fn find_order(&self, reference: &str) -> Result<Option<Order>>;
+    /// Returns the order a transaction pays, or the latest one it failed to.
+    fn find_order_by_signature(&self, signature: &str) -> Result<Option<Order>>;
+    /// Returns the pending orders, oldest first.
+    fn pending_orders(&self) -> Result<Vec<Order>>;
+    /// Sets the status of a pending order and of its items. Returns `false`
+    /// if the order is not pending.
+    fn set_order_status(
+        &self,
+        id: u64,
+        status: PurchaseStatus,
+        error: Option<&str>,
+    ) -> Result<bool>;
+    /// Confirms a pending order paid by a transaction the server did not
+    /// submit. Returns `false` if the order is not pending, or if the
+    /// transaction already pays another order which did not fail.
+    fn confirm_order(&self, id: u64, buyer: &str, signature: &str) -> Result<bool>;
+    /// Records where the transaction paying an order landed, keeping the fee
+    /// already known if `receipt` has none.
+    fn set_order_receipt(&self, id: u64, receipt: &Receipt) -> Result<()>;
+    /// Returns the purchases matching `filter`, newest first.
+    fn list(&self, filter: &PurchaseFilter) -> Result<Vec<Purchase>>;
+}
+
+pub trait IdempotencyStore: Send + Sync {
+    /// Reserves `key` for a request with the given body fingerprint, or
+    /// returns the record of the first request which used it. A reservation
+    /// made before `stale_before` and still without a response is taken over.
+    fn reserve(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        now: i64,
+        stale_before: i64,
+    ) -> Result<Option<IdempotencyRecord>>;
+    /// Stores the response of a reserved key, unless one already is.
+    fn complete(&self, key: &str, response: &StoredResponse) -> Result<()>;
+    /// Frees a reserved key without a response, so it can be used again.
+    fn release(&self, key: &str) -> Result<()>;
+}
+
+pub trait WebhookStore: Send + Sync {
+    fn get_webhook(&self, vendor: &str) -> Result<Option<Webhook>>;
+    /// Registers or replaces the webhook of a vendor.
+    fn set_webhook(&self, webhook: &Webhook) -> Result<()>;
+    /// Returns `false` if the vendor has no webhook.
+    fn delete_webhook(&self, vendor: &str) -> Result<bool>;
+    /// Queues a delivery and returns its id, the given one is ignored.
+    fn insert_delivery(&self, delivery: &Delivery) -> Result<u64>;
+    fn get_delivery(&self, id: u64) -> Result<Option<Delivery>>;
+    /// Returns the pending deliveries due at `now`, oldest first, at most
+    /// `per_vendor` of each vendor so a backlog does not starve the others.
+    fn due_deliveries(&self, now: i64, per_vendor: u32, limit: u32) -> Result<Vec<Delivery>>;
+    /// Updates the status, attempts and outcome of a delivery.
+    fn update_delivery(&self, delivery: &Delivery) -> Result<()>;
+    /// Returns the deliveries matching `filter`, newest first.
+    fn list_deliveries(&self, filter: &DeliveryFilter) -> Result<Vec<Delivery>>;
+}
+
+pub trait JobStore: Send + Sync {
+    /// Queues a job and returns its id, the given one is ignored.
+    fn insert_job(&self, job: &Job) -> Result<u64>;
+    fn get_job(&self, id: u64) -> Result<Option<Job>>;
+    /// Returns the latest job submitting a transaction.
+    fn find_job_by_signature(&self, signature: &str) -> Result<Option<Job>>;
+    /// Marks the oldest queued job due at `now` as running, and returns it.
+    /// A job is only ever claimed by a single worker.
+    fn claim_job(&self, now: i64) -> Result<Option<Job>>;
+    /// Updates the status, attempts, outcome and order of a job.
+    fn update_job(&self, job: &Job) -> Result<()>;
+    /// Queues the running jobs again, returning how many there were.
+    fn requeue_running_jobs(&self) -> Result<usize>;
+}
+
+/// Handles on the same backend, one per record type.
+#[derive(Clone)]
+pub struct Stores {
+    pub vendors: Arc<dyn VendorStore>,
+    pub purchases: Arc<dyn PurchaseStore>,
+    pub idempotency: Arc<dyn IdempotencyStore>,
+    pub webhooks: Arc<dyn WebhookStore>,
+    pub jobs: Arc<dyn JobStore>,
+}
+
+impl Stores {
+    fn new<S>(store: S) -> Self
+    where
+        S: VendorStore + PurchaseStore + IdempotencyStore + WebhookStore + JobStore + 'static,
+    {
+        let store = Arc::new(store);
+        Self {
+            vendors: store.clone(),
+            purchases: store.clone(),
+            idempotency: store.clone(),
+            webhooks: store.clone(),
+            jobs: store,
+        }
+    }
+}
+
+/// Opens the store selected by `kind`, `path` is only used by sqlite.
+pub fn open(kind: StoreKind, path: &Path) -> Result<Stores> {
+    match kind {
+        StoreKind::Memory => Ok(Stores::new(MemoryStore::default())),
+        StoreKind::Sqlite => Ok(Stores::new(SqliteStore::open(path)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{buy::jobs::JobStatus, webhooks::DeliveryStatus};
+    use solana_transaction_status::TransactionConfirmationStatus;
+
+    /// Every backend, sqlite on a fresh in-memory database.
+    fn backends() -> [Stores; 2] {
+        [
+            open(StoreKind::Memory, Path::new("")).unwrap(),
+            open(StoreKind::Sqlite, Path::new(":memory:")).unwrap(),
+        ]
+    }
+
+    fn order(signature: &str) -> Order {
+        let item = Purchase::item("vendor".into(), "coffee".into(), 2, 10, None);
+        Order::pending("buyer".into(), signature.into(), vec![item])
+    }
+
+    fn job(signature: &str) -> Job {
+        Job {
+            id: 0,
+            created_at: 0,
+            buyer: "buyer".into(),
+            signature: signature.into(),
+            service: "coffee".into(),
+            transaction: String::new(),
+            status: JobStatus::Queued,
+            attempts: 0,
+            next_attempt_at: 100,
+            error: None,
+            order_id: None,
+        }
+    }
+
+    #[test]
+    fn reapplies_only_rejected_vendors() {
+        for stores in backends() {
+            let vendor = Vendor {
+                wallet_id: "wallet".into(),
+                name: "shop".into(),
+                address: String::new(),
+                services: Vec::new(),
+                accepted_mints: Vec::new(),
+                status: VendorStatus::Pending,
+            };
+            assert!(stores.vendors.insert(&vendor).unwrap());
+            assert!(!stores.vendors.insert(&vendor).unwrap());
+            assert!(!stores.vendors.reapply(&vendor).unwrap());
+            assert!(stores
+                .vendors
+                .set_status("wallet", VendorStatus::Rejected)
+                .unwrap());
+            assert!(stores.vendors.reapply(&vendor).unwrap());
+            let stored = stores.vendors.get("wallet").unwrap().unwrap();
+            assert_eq!(stored.status, VendorStatus::Pending);
+        }
+    }
+
+    #[test]
+    fn settles_orders_once_and_pays_one_order_per_transaction() {
+        for stores in backends() {
+            let purchases = &stores.purchases;
+            let id = purchases.insert_order(&order("paid")).unwrap().unwrap();
+            assert_eq!(purchases.insert_order(&order("paid")).unwrap(), None);
+            assert!(purchases
+                .set_order_status(id, PurchaseStatus::Confirmed, None)
+                .unwrap());
+            assert!(!purchases
+                .set_order_status(id, PurchaseStatus::Failed, Some("late"))
+                .unwrap());
+            let stored = purchases.get_order(id).unwrap().unwrap();
+            assert_eq!(stored.status, PurchaseStatus::Confirmed);
+            assert_eq!(stored.items[0].status, PurchaseStatus::Confirmed);
+            assert_eq!(stored.items[0].quantity, 2);
+            let found = purchases.find_order_by_signature("paid").unwrap().unwrap();
+            assert_eq!(found.id, id);
+
+            // a solana pay request, its signature is only known once paid
+            let request = purchases.insert_order(&order("")).unwrap().unwrap();
+            assert!(!purchases.confirm_order(request, "payer", "paid").unwrap());
+            assert!(purchases.confirm_order(request, "payer", "other").unwrap());
+            assert!(!purchases.confirm_order(request, "payer", "other").unwrap());
+            let stored = purchases.get_order(request).unwrap().unwrap();
+            assert_eq!(
+                (stored.buyer.as_str(), stored.status),
+                ("payer", PurchaseStatus::Confirmed)
+            );
+            assert!(purchases.pending_orders().unwrap().is_empty());
+            assert_eq!(purchases.list(&PurchaseFilter::default()).unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn records_receipts_keeping_the_known_fee() {
+        for stores in backends() {
+            let purchases = &stores.purchases;
+            let id = purchases.insert_order(&order("paid")).unwrap().unwrap();
+            let receipt = Receipt {
+                commitment: Some(TransactionConfirmationStatus::Confirmed),
+                slot: Some(10),
+                fee: Some(5000),
+            };
+            purchases.set_order_receipt(id, &receipt).unwrap();
+            let finalized = Receipt {
+                commitment: Some(TransactionConfirmationStatus::Finalized),
+                slot: Some(12),
+                fee: None,
+            };
+            purchases.set_order_receipt(id, &finalized).unwrap();
+            let stored = purchases.get_order(id).unwrap().unwrap().receipt;
+            assert_eq!(
+                stored.commitment,
+                Some(TransactionConfirmationStatus::Finalized)
+            );
+            assert_eq!((stored.slot, stored.fee), (Some(12), Some(5000)));
+        }
+    }
+
+    #[test]
+    fn caps_due_deliveries_per_vendor() {
+        for stores in backends() {
+            let order_id = stores.purchases.insert_order(&order("")).unwrap().unwrap();
+            for vendor in ["busy", "busy", "busy", "idle", "busy"] {
+                let delivery = Delivery {
+                    id: 0,
+                    created_at: 0,
+                    vendor: vendor.into(),
+                    event: "purchase.confirmed".into(),
+                    order_id,
+                    payload: serde_json::json!({}),
+                    status: DeliveryStatus::Pending,
+                    attempts: 0,
+                    next_attempt_at: 0,
+                    response_status: None,
+                    error: None,
+                };
+                stores.webhooks.insert_delivery(&delivery).unwrap();
+            }
+            let due = stores.webhooks.due_deliveries(0, 2, 20).unwrap();
+            let ids: Vec<_> = due.iter().map(|delivery| delivery.id).collect();
+            assert_eq!(ids, [1, 2, 4]);
+            assert_eq!(stores.webhooks.due_deliveries(0, 2, 2).unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn claims_each_job_once() {
+        for stores in backends() {
+            let jobs = &stores.jobs;
+            let id = jobs.insert_job(&job("sig")).unwrap();
+            assert!(jobs.claim_job(99).unwrap().is_none());
+            let mut claimed = jobs.claim_job(100).unwrap().unwrap();
+            assert_eq!((claimed.id, claimed.status), (id, JobStatus::Running));
+            assert!(jobs.claim_job(100).unwrap().is_none());
+
+            assert_eq!(jobs.requeue_running_jobs().unwrap(), 1);
+            claimed = jobs.claim_job(100).unwrap().unwrap();
+            claimed.status = JobStatus::Failed;
+            claimed.attempts = 1;
+            claimed.error = Some("rejected".into());
+            jobs.update_job(&claimed).unwrap();
+            assert_eq!(jobs.requeue_running_jobs().unwrap(), 0);
+            let found = jobs.find_job_by_signature("sig").unwrap().unwrap();
+            assert_eq!((found.id, found.status), (id, JobStatus::Failed));
+            assert_eq!(found.error.as_deref(), Some("rejected"));
+        }
+    }
+}
@@ -0,0 +1,1098 @@
+use super::{IdempotencyStore, JobStore, PurchaseStore, VendorStore, WebhookStore};
+use crate::{
+    buy::jobs::{Job, JobStatus},
+    idempotency::{IdempotencyRecord, StoredResponse},
+    purchases::{Order, Purchase, PurchaseFilter, PurchaseStatus, Receipt},
+    vendors::{Vendor, VendorStatus},
+    webhooks::{Delivery, DeliveryFilter, DeliveryStatus, Webhook},
+};
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::de::DeserializeOwned;
+use solana_transaction_status::TransactionConfirmationStatus;
+use std::{path::Path, sync::Mutex};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tracing::info;
+
+/// Schema migrations, applied in order. The index of a migration plus one is
+/// stored in `PRAGMA user_version` once it has run.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE vendors (
+        wallet_id TEXT PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL,
+        address TEXT NOT NULL DEFAULT '',
+        services TEXT NOT NULL DEFAULT '[]'
+    );",
+    "CREATE TABLE purchases (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        created_at INTEGER NOT NULL,
+        buyer TEXT NOT NULL,
+        vendor TEXT NOT NULL,
+        lamports INTEGER NOT NULL,
+        signature TEXT NOT NULL,
+        status TEXT NOT NULL,
+        error TEXT
+    );
+    CREATE INDEX purchases_vendor ON purchases (vendor, created_at);
+    CREATE INDEX purchases_buyer ON purchases (buyer, created_at);",
+    "CREATE TABLE idempotency_keys (
+        key TEXT PRIMARY KEY NOT NULL,
+        fingerprint TEXT NOT NULL,
+        status INTEGER,
+        response TEXT
+    );",
+    // vendors which existed before onboarding were whitelisted, keep them so
+    "ALTER TABLE vendors ADD COLUMN status TEXT NOT NULL DEFAULT 'approved';",
+    "ALTER TABLE vendors ADD COLUMN accepted_mints TEXT NOT NULL DEFAULT '[]';
+    ALTER TABLE purchases RENAME COLUMN lamports TO amount;
+    ALTER TABLE purchases ADD COLUMN mint TEXT;",
+    // services used to be plain names, they are kept unpriced and inactive
+    "UPDATE vendors SET services = (
+        SELECT json_group_array(json_object(
+            'id', value, 'name', value, 'description', '', 'price', 0, 'mint', NULL,
+            'active', json('false')
+        ))
+        FROM json_each(vendors.services)
+    );
+    ALTER TABLE purchases ADD COLUMN service TEXT;",
+    "CREATE TABLE orders (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        created_at INTEGER NOT NULL,
+        buyer TEXT NOT NULL,
+        signature TEXT NOT NULL,
+        status TEXT NOT NULL,
+        error TEXT
+    );
+    ALTER TABLE purchases ADD COLUMN order_id INTEGER REFERENCES orders (id);
+    ALTER TABLE purchases ADD COLUMN quantity INTEGER NOT NULL DEFAULT 1;
+    CREATE INDEX purchases_order ON purchases (order_id);",
+    "ALTER TABLE orders ADD COLUMN reference TEXT;
+    ALTER TABLE orders ADD COLUMN memo TEXT;
+    ALTER TABLE orders ADD COLUMN url TEXT;
+    CREATE UNIQUE INDEX orders_reference ON orders (reference);",
+    "CREATE INDEX orders_status ON orders (status);",
+    "CREATE TABLE webhooks (
+        vendor TEXT PRIMARY KEY NOT NULL,
+        url TEXT NOT NULL,
+        secret TEXT NOT NULL
+    );
+    CREATE TABLE webhook_deliveries (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        created_at INTEGER NOT NULL,
+        vendor TEXT NOT NULL,
+        event TEXT NOT NULL,
+        order_id INTEGER NOT NULL REFERENCES orders (id),
+        payload TEXT NOT NULL,
+        status TEXT NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL,
+        response_status INTEGER,
+        error TEXT
+    );
+    CREATE INDEX webhook_deliveries_due ON webhook_deliveries (status, next_attempt_at);
+    CREATE INDEX webhook_deliveries_vendor ON webhook_deliveries (vendor, id);",
+    "CREATE TABLE purchase_jobs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        created_at INTEGER NOT NULL,
+        buyer TEXT NOT NULL,
+        signature TEXT NOT NULL,
+        service TEXT NOT NULL,
+        [transaction] TEXT NOT NULL,
+        status TEXT NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL,
+        error TEXT,
+        order_id INTEGER REFERENCES orders (id)
+    );
+    CREATE INDEX purchase_jobs_due ON purchase_jobs (status, next_attempt_at);",
+    // a transaction pays a single order, duplicates which did not fail are failed
+    "UPDATE orders SET status = 'failed', error = 'transaction already pays order ' || (
+        SELECT MIN(first.id) FROM orders AS first
+        WHERE first.signature = orders.signature AND first.status != 'failed'
+    )
+    WHERE signature != '' AND status != 'failed' AND id > (
+        SELECT MIN(first.id) FROM orders AS first
+        WHERE first.signature = orders.signature AND first.status != 'failed'
+    );
+    UPDATE purchases SET
+        status = (SELECT status FROM orders WHERE orders.id = purchases.order_id),
+        error = (SELECT error FROM orders WHERE orders.id = purchases.order_id)
+    WHERE order_id IS NOT NULL;
+    CREATE UNIQUE INDEX orders_signature ON orders (signature)
+        WHERE signature != '' AND status != 'failed';",
+    // keys reserved before leases existed have no known age, they are freed
+    "ALTER TABLE idempotency_keys ADD COLUMN reserved_at INTEGER NOT NULL DEFAULT 0;",
+    "CREATE INDEX orders_by_signature ON orders (signature);
+    CREATE INDEX purchase_jobs_signature ON purchase_jobs (signature);",
+    "ALTER TABLE orders ADD COLUMN commitment TEXT;
+    ALTER TABLE orders ADD COLUMN slot INTEGER;
+    ALTER TABLE orders ADD COLUMN fee INTEGER;",
+];
+
+/// Columns read by [`job_from_row`].
+const JOB_COLUMNS: &str = "id, created_at, buyer, signature, service, [transaction], status, \
+    attempts, next_attempt_at, error, order_id";
+
+/// Columns read by [`delivery_from_row`].
+const DELIVERY_COLUMNS: &str = "id, created_at, vendor, event, order_id, payload, status, \
+    attempts, next_attempt_at, response_status, error";
+
+/// Columns read by [`order_from_row`].
+const ORDER_COLUMNS: &str =
+    "id, created_at, buyer, signature, status, error, reference, memo, url, commitment, slot, fee";
+
+/// Columns read by [`purchase_from_row`].
+const PURCHASE_COLUMNS: &str = "id, order_id, created_at, buyer, vendor, service, quantity, \
+    amount, mint, signature, status, error";
+
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut conn = Connection::open(path)
+            .with_context(|| format!("failed to open database at {}", path.display()))?;
+        migrate(&mut conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl VendorStore for SqliteStore {
+    fn get(&self, wallet_id: &str) -> Result<Option<Vendor>> {
+        blocking(|| {
+            let vendor = self
+                .conn
+                .lock()
+                .unwrap()
+                .query_row(
+                    "SELECT wallet_id, name, address, services, status, accepted_mints FROM vendors WHERE wallet_id = ?1",
+                    [wallet_id],
+                    vendor_from_row,
+                )
+                .optional()?;
+            Ok(vendor)
+        })
+    }
+
+    fn list(&self) -> Result<Vec<Vendor>> {
+        blocking(|| {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare(
+                "SELECT wallet_id, name, address, services, status, accepted_mints FROM vendors ORDER BY rowid",
+            )?;
+            let vendors = stmt
+                .query_map([], vendor_from_row)?
+                .collect::<Result<_, _>>()?;
+            Ok(vendors)
+        })
+    }
+
+    fn insert(&self, vendor: &Vendor) -> Result<bool> {
+        blocking(|| {
+            let inserted = self.conn.lock().unwrap().execute(
+                "INSERT OR IGNORE INTO vendors (wallet_id, name, address, services, status, accepted_mints)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    vendor.wallet_id,
+                    vendor.name,
+                    vendor.address,
+                    serde_json::to_string(&vendor.services)?,
+                    vendor.status.as_str(),
+                    serde_json::to_string(&vendor.accepted_mints)?
+                ],
+            )?;
+            Ok(inserted > 0)
+        })
+    }
+
+    fn insert_all(&self, vendors: &[Vendor]) -> Result<Vec<String>> {
+        blocking(|| {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            let mut existing = Vec::new();
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT OR IGNORE INTO vendors
+                    (wallet_id, name, address, services, status, accepted_mints)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                )?;
+                for vendor in vendors {
+                    let inserted = stmt.execute(params![
+                        vendor.wallet_id,
+                        vendor.name,
+                        vendor.address,
+                        serde_json::to_string(&vendor.services)?,
+                        vendor.status.as_str(),
+                        serde_json::to_string(&vendor.accepted_mints)?
+                    ])?;
+                    if inserted == 0 {
+                        existing.push(vendor.wallet_id.clone());
+                    }
+                }
+            }
+            if existing.is_empty() {
+                tx.commit()?;
+            }
+            Ok(existing)
+        })
+    }
+
+    fn update(&self, vendor: &Vendor) -> Result<bool> {
+        blocking(|| {
+            let updated = self.conn.lock().unwrap().execute(
+                "UPDATE vendors SET name = ?2, address = ?3, services = ?4, accepted_mints = ?5
+                WHERE wallet_id = ?1",
+                params![
+                    vendor.wallet_id,
+                    vendor.name,
+                    vendor.address,
+                    serde_json::to_string(&vendor.services)?,
+                    serde_json::to_string(&vendor.accepted_mints)?
+                ],
+            )?;
+            Ok(updated > 0)
+        })
+    }
+
+    fn reapply(&self, vendor: &Vendor) -> Result<bool> {
+        blocking(|| {
+            let updated = self.conn.lock().unwrap().execute(
+                "UPDATE vendors
+                SET name = ?2, address = ?3, services = ?4, accepted_mints = ?5, status = 'pending'
+                WHERE wallet_id = ?1 AND status = 'rejected'",
+                params![
+                    vendor.wallet_id,
+                    vendor.name,
+                    vendor.address,
+                    serde_json::to_string(&vendor.services)?,
+                    serde_json::to_string(&vendor.accepted_mints)?
+                ],
+            )?;
+            Ok(updated > 0)
+        })
+    }
+
+    fn set_status(&self, wallet_id: &str, status: VendorStatus) -> Result<bool> {
+        blocking(|| {
+            let updated = self.conn.lock().unwrap().execute(
+                "UPDATE vendors SET status = ?2 WHERE wallet_id = ?1",
+                params![wallet_id, status.as_str()],
+            )?;
+            Ok(updated > 0)
+        })
+    }
+
+    fn delete(&self, wallet_id: &str) -> Result<bool> {
+        blocking(|| {
+            let deleted = self
+                .conn
+                .lock()
+                .unwrap()
+                .execute("DELETE FROM vendors WHERE wallet_id = ?1", [wallet_id])?;
+            Ok(deleted > 0)
+        })
+    }
+}
+
+impl PurchaseStore for SqliteStore {
+    fn insert_order(&self, order: &Order) -> Result<Option<u64>> {
+        blocking(|| {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            let inserted = tx.execute(
+                "INSERT INTO orders (created_at, buyer, signature, status, error, reference, memo, url)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    order.created_at,
+                    order.buyer,
+                    order.signature,
+                    order.status.as_str(),
+                    order.error,
+                    order.reference,
+                    order.memo,
+                    order.url
+                ],
+            );
+            match inserted {
+                Err(e) if is_unique_violation(&e, "orders.signature") => return Ok(None),
+                inserted => inserted?,
+            };
+            let id = tx.last_insert_rowid() as u64;
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT INTO purchases (order_id, created_at, buyer, vendor, service, quantity,
+                    amount, mint, signature, status, error)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                )?;
+                for purchase in &order.items {
+                    stmt.execute(params![
+                        id,
+                        purchase.created_at,
+                        purchase.buyer,
+                        purchase.vendor,
+                        purchase.service,
+                        purchase.quantity,
+                        purchase.amount,
+                        purchase.mint,
+                        purchase.signature,
+                        purchase.status.as_str(),
+                        purchase.error
+                    ])?;
+                }
+            }
+            tx.commit()?;
+            Ok(Some(id))
+        })
+    }
+
+    fn get_order(&self, id: u64) -> Result<Option<Order>> {
+        blocking(|| {
+            let conn = self.conn.lock().unwrap();
+            let order = conn
+                .query_row(
+                    &format!("SELECT {ORDER_COLUMNS} FROM orders WHERE id = ?1"),
+                    [id],
+                    order_from_row,
+                )
+                .optional()?;
+            order.map(|order| with_items(&conn, order)).transpose()
+        })
+    }
+
+    fn find_order(&self, reference: &str) -> Result<Option<Order>> {
+        blocking(|| {
+            let conn = self.conn.lock().unwrap();
+            let order = conn
+                .query_row(
+                    &format!("SELECT {ORDER_COLUMNS} FROM orders WHERE reference = ?1"),
+                    [reference],
+                    order_from_row,
+                )
+                .optional()?;
+            order.map(|order| with_items(&conn, order)).transpose()
+        })
+    }
+
+    fn find_order_by_signature(&self, signature: &str) -> Result<Option<Order>> {
+        blocking(|| {
+            let conn = self.conn.lock().unwrap();
+            let order = conn
+                .query_row(
+                    &format!(
+                        "SELECT {ORDER_COLUMNS} FROM orders
+                        WHERE signature = ?1 AND signature != ''
+                        ORDER BY status = 'failed', id DESC
+                        LIMIT 1"
+                    ),
+                    [signature],
+                    order_from_row,
+                )
+                .optional()?;
+            order.map(|order| with_items(&conn, order)).transpose()
+        })
+    }
+
+    fn set_order_status(
+        &self,
+        id: u64,
+        status: PurchaseStatus,
+        error: Option<&str>,
+    ) -> Result<bool> {
+        blocking(|| {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            let updated = tx.execute(
+                "UPDATE orders SET status = ?2, error = ?3 WHERE id = ?1 AND status = 'pending'",
+                params![id, status.as_str(), error],
+            )?;
+            if updated == 0 {
+                return Ok(false);
+            }
+            tx.execute(
+                "UPDATE purchases SET status = ?2, error = ?3 WHERE order_id = ?1",
+                params![id, status.as_str(), error],
+            )?;
+            tx.commit()?;
+            Ok(true)
+        })
+    }
+
+    fn set_order_receipt(&self, id: u64, receipt: &Receipt) -> Result<()> {
+        blocking(|| {
+            let commitment = serde_json::to_value(&receipt.commitment)?;
+            self.conn.lock().unwrap().execute(
+                "UPDATE orders SET commitment = ?2, slot = ?3, fee = COALESCE(?4, fee) WHERE id = ?1",
+                params![id, commitment.as_str(), receipt.slot, receipt.fee],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn pending_orders(&self) -> Result<Vec<Order>> {
+        blocking(|| {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {ORDER_COLUMNS} FROM orders WHERE status = 'pending' ORDER BY id"
+            ))?;
+            let orders = stmt
+                .query_map([], order_from_row)?
+                .collect::<Result<Vec<_>, _>>()?;
+            orders
+                .into_iter()
+                .map(|order| with_items(&conn, order))
+                .collect()
+        })
+    }
+
+    fn confirm_order(&self, id: u64, buyer: &str, signature: &str) -> Result<bool> {
+        blocking(|| {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            let updated = tx.execute(
+                "UPDATE orders SET buyer = ?2, signature = ?3, status = 'confirmed', error = NULL
+                WHERE id = ?1 AND status = 'pending'",
+                params![id, buyer, signature],
+            );
+            match updated {
+                Ok(0) => return Ok(false),
+                Err(e) if is_unique_violation(&e, "orders.signature") => return Ok(false),
+                updated => updated?,
+            };
+            tx.execute(
+                "UPDATE purchases SET buyer = ?2, signature = ?3, status = 'confirmed', error = NULL
+                WHERE order_id = ?1",
+                params![id, buyer, signature],
+            )?;
+            tx.commit()?;
+            Ok(true)
+        })
+    }
+
+    fn list(&self, filter: &PurchaseFilter) -> Result<Vec<Purchase>> {
+        blocking(|| {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {PURCHASE_COLUMNS}
+                FROM purchases
+                WHERE (?1 IS NULL OR vendor = ?1)
+                    AND (?2 IS NULL OR buyer = ?2)
+                    AND (?3 IS NULL OR created_at >= ?3)
+                    AND (?4 IS NULL OR created_at < ?4)
+                ORDER BY id DESC
+                LIMIT ?5 OFFSET ?6"
+            ))?;
+            let purchases = stmt
+                .query_map(
+                    params![
+                        filter.vendor,
+                        filter.buyer,
+                        filter.since,
+                        filter.until,
+                        filter.limit(),
+                        filter.offset
+                    ],
+                    purchase_from_row,
+                )?
+                .collect::<Result<_, _>>()?;
+            Ok(purchases)
+        })
+    }
+}
+
+impl IdempotencyStore for SqliteStore {
+    fn reserve(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        now: i64,
+        stale_before: i64,
+    ) -> Result<Option<IdempotencyRecord>> {
+        blocking(|| {
+            let conn = self.conn.lock().unwrap();
+            let reserved = conn.execute(
+                "INSERT INTO idempotency_keys (key, fingerprint, reserved_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT (key) DO UPDATE SET
+                    fingerprint = excluded.fingerprint,
+                    reserved_at = excluded.reserved_at
+                WHERE status IS NULL AND reserved_at < ?4",
+                params![key, fingerprint, now, stale_before],
+            )?;
+            if reserved > 0 {
+                return Ok(None);
+            }
+            let (fingerprint, reserved_at, status, response): (
+                String,
+                i64,
+                Option<u16>,
+                Option<String>,
+            ) = conn.query_row(
+                "SELECT fingerprint, reserved_at, status, response FROM idempotency_keys
+                WHERE key = ?1",
+                [key],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )?;
+            let response = match (status, response) {
+                (Some(status), Some(body)) => Some(StoredResponse {
+                    status,
+                    body: serde_json::from_str(&body)?,
+                }),
+                _ => None,
+            };
+            Ok(Some(IdempotencyRecord {
+                fingerprint,
+                reserved_at,
+                response,
+            }))
+        })
+    }
+
+    fn complete(&self, key: &str, response: &StoredResponse) -> Result<()> {
+        blocking(|| {
+            self.conn.lock().unwrap().execute(
+                "UPDATE idempotency_keys SET status = ?2, response = ?3
+                WHERE key = ?1 AND status IS NULL",
+                params![key, response.status, response.body.to_string()],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn release(&self, key: &str) -> Result<()> {
+        blocking(|| {
+            self.conn.lock().unwrap().execute(
+                "DELETE FROM idempotency_keys WHERE key = ?1 AND status IS NULL",
+                [key],
+            )?;
+            Ok(())
+        })
+    }
+}
+
+impl WebhookStore for SqliteStore {
+    fn get_webhook(&self, vendor: &str) -> Result<Option<Webhook>> {
+        blocking(|| {
+            let webhook = self
+                .conn
+                .lock()
+                .unwrap()
+                .query_row(
+                    "SELECT vendor, url, secret FROM webhooks WHERE vendor = ?1",
+                    [vendor],
+                    |row| {
+                        Ok(Webhook {
+                            vendor: row.get(0)?,
+                            url: row.get(1)?,
+                            secret: row.get(2)?,
+                        })
+                    },
+                )
+                .optional()?;
+            Ok(webhook)
+        })
+    }
+
+    fn set_webhook(&self, webhook: &Webhook) -> Result<()> {
+        blocking(|| {
+            self.conn.lock().unwrap().execute(
+                "INSERT INTO webhooks (vendor, url, secret) VALUES (?1, ?2, ?3)
+                ON CONFLICT (vendor) DO UPDATE SET url = excluded.url, secret = excluded.secret",
+                params![webhook.vendor, webhook.url, webhook.secret],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn delete_webhook(&self, vendor: &str) -> Result<bool> {
+        blocking(|| {
+            let deleted = self
+                .conn
+                .lock()
+                .unwrap()
+                .execute("DELETE FROM webhooks WHERE vendor = ?1", [vendor])?;
+            Ok(deleted > 0)
+        })
+    }
+
+    fn insert_delivery(&self, delivery: &Delivery) -> Result<u64> {
+        blocking(|| {
+            let conn = self.conn.lock().unwrap();
+            conn.execute(
+                "INSERT INTO webhook_deliveries (created_at, vendor, event, order_id, payload, status,
+                attempts, next_attempt_at, response_status, error)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    delivery.created_at,
+                    delivery.vendor,
+                    delivery.event,
+                    delivery.order_id,
+                    delivery.payload.to_string(),
+                    delivery.status.as_str(),
+                    delivery.attempts,
+                    delivery.next_attempt_at,
+                    delivery.response_status,
+                    delivery.error
+                ],
+            )?;
+            Ok(conn.last_insert_rowid() as u64)
+        })
+    }
+
+    fn get_delivery(&self, id: u64) -> Result<Option<Delivery>> {
+        blocking(|| {
+            let delivery = self
+                .conn
+                .lock()
+                .unwrap()
+                .query_row(
+                    &format!("SELECT {DELIVERY_COLUMNS} FROM webhook_deliveries WHERE id = ?1"),
+                    [id],
+                    delivery_from_row,
+                )
+                .optional()?;
+            Ok(delivery)
+        })
+    }
+
+    fn due_deliveries(&self, now: i64, per_vendor: u32, limit: u32) -> Result<Vec<Delivery>> {
+        blocking(|| {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {DELIVERY_COLUMNS} FROM (
+                    SELECT *, ROW_NUMBER() OVER (PARTITION BY vendor ORDER BY id) AS rank
+                    FROM webhook_deliveries
+                    WHERE status = 'pending' AND next_attempt_at <= ?1
+                )
+                WHERE rank <= ?2
+                ORDER BY id
+                LIMIT ?3"
+            ))?;
+            let deliveries = stmt
+                .query_map(params![now, per_vendor, limit], delivery_from_row)?
+                .collect::<Result<_, _>>()?;
+            Ok(deliveries)
+        })
+    }
+
+    fn update_delivery(&self, delivery: &Delivery) -> Result<()> {
+        blocking(|| {
+            self.conn.lock().unwrap().execute(
+                "UPDATE webhook_deliveries
+                SET status = ?2, attempts = ?3, next_attempt_at = ?4, response_status = ?5, error = ?6
+                WHERE id = ?1",
+                params![
+                    delivery.id,
+                    delivery.status.as_str(),
+                    delivery.attempts,
+                    delivery.next_attempt_at,
+                    delivery.response_status,
+                    delivery.error
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn list_deliveries(&self, filter: &DeliveryFilter) -> Result<Vec<Delivery>> {
+        blocking(|| {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {DELIVERY_COLUMNS}
+                FROM webhook_deliveries
+                WHERE (?1 IS NULL OR vendor = ?1)
+                    AND (?2 IS NULL OR status = ?2)
+                ORDER BY id DESC
+                LIMIT ?3 OFFSET ?4"
+            ))?;
+            let deliveries = stmt
+                .query_map(
+                    params![
+                        filter.vendor,
+                        filter.status.map(DeliveryStatus::as_str),
+                        filter.limit(),
+                        filter.offset
+                    ],
+                    delivery_from_row,
+                )?
+                .collect::<Result<_, _>>()?;
+            Ok(deliveries)
+        })
+    }
+}
+
+impl JobStore for SqliteStore {
+    fn insert_job(&self, job: &Job) -> Result<u64> {
+        blocking(|| {
+            let conn = self.conn.lock().unwrap();
+            conn.execute(
+                "INSERT INTO purchase_jobs (created_at, buyer, signature, service, [transaction],
+                status, attempts, next_attempt_at, error, order_id)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    job.created_at,
+                    job.buyer,
+                    job.signature,
+                    job.service,
+                    job.transaction,
+                    job.status.as_str(),
+                    job.attempts,
+                    job.next_attempt_at,
+                    job.error,
+                    job.order_id
+                ],
+            )?;
+            Ok(conn.last_insert_rowid() as u64)
+        })
+    }
+
+    fn get_job(&self, id: u64) -> Result<Option<Job>> {
+        blocking(|| {
+            let job = self
+                .conn
+                .lock()
+                .unwrap()
+                .query_row(
+                    &format!("SELECT {JOB_COLUMNS} FROM purchase_jobs WHERE id = ?1"),
+                    [id],
+                    job_from_row,
+                )
+                .optional()?;
+            Ok(job)
+        })
+    }
+
+    fn find_job_by_signature(&self, signature: &str) -> Result<Option<Job>> {
+        blocking(|| {
+            let job = self
+                .conn
+                .lock()
+                .unwrap()
+                .query_row(
+                    &format!(
+                        "SELECT {JOB_COLUMNS} FROM purchase_jobs
+                        WHERE signature = ?1
+                        ORDER BY id DESC
+                        LIMIT 1"
+                    ),
+                    [signature],
+                    job_from_row,
+                )
+                .optional()?;
+            Ok(job)
+        })
+    }
+
+    fn claim_job(&self, now: i64) -> Result<Option<Job>> {
+        blocking(|| {
+            let job = self
+                .conn
+                .lock()
+                .unwrap()
+                .query_row(
+                    &format!(
+                        "UPDATE purchase_jobs SET status = 'running'
+                        WHERE id = (
+                            SELECT id FROM purchase_jobs
+                            WHERE status = 'queued' AND next_attempt_at <= ?1
+                            ORDER BY id
+                            LIMIT 1
+                        )
+                        RETURNING {JOB_COLUMNS}"
+                    ),
+                    [now],
+                    job_from_row,
+                )
+                .optional()?;
+            Ok(job)
+        })
+    }
+
+    fn update_job(&self, job: &Job) -> Result<()> {
+        blocking(|| {
+            self.conn.lock().unwrap().execute(
+                "UPDATE purchase_jobs
+                SET status = ?2, attempts = ?3, next_attempt_at = ?4, error = ?5, order_id = ?6
+                WHERE id = ?1",
+                params![
+                    job.id,
+                    job.status.as_str(),
+                    job.attempts,
+                    job.next_attempt_at,
+                    job.error,
+                    job.order_id
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn requeue_running_jobs(&self) -> Result<usize> {
+        blocking(|| {
+            let count = self.conn.lock().unwrap().execute(
+                "UPDATE purchase_jobs SET status = 'queued' WHERE status = 'running'",
+                [],
+            )?;
+            Ok(count)
+        })
+    }
+}
+
+/// Runs a query without stalling the other tasks of the runtime worker it is
+/// called from, which move to another worker meanwhile. Outside a multi
+/// threaded runtime, in commands and tests, the query just runs.
+fn blocking<T>(query: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(query)
+        }
+        _ => query(),
+    }
+}
+
+fn vendor_from_row(row: &Row) -> rusqlite::Result<Vendor> {
+    let status: String = row.get(4)?;
+    Ok(Vendor {
+        wallet_id: row.get(0)?,
+        name: row.get(1)?,
+        address: row.get(2)?,
+        services: json_column(row, 3)?,
+        accepted_mints: json_column(row, 5)?,
+        status: VendorStatus::parse(&status).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(
+                4,
+                rusqlite::types::Type::Text,
+                format!("unknown vendor status {status}").into(),
+            )
+        })?,
+    })
+}
+
+fn json_column<T: DeserializeOwned>(row: &Row, i: usize) -> rusqlite::Result<T> {
+    let value: String = row.get(i)?;
+    serde_json::from_str(&value).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(i, rusqlite::types::Type::Text, e.into())
+    })
+}
+
+fn purchase_from_row(row: &Row) -> rusqlite::Result<Purchase> {
+    let amount = row.get(7)?;
+    let mint: Option<String> = row.get(8)?;
+    Ok(Purchase {
+        id: row.get(0)?,
+        order_id: row.get(1)?,
+        created_at: row.get(2)?,
+        buyer: row.get(3)?,
+        vendor: row.get(4)?,
+        service: row.get(5)?,
+        quantity: row.get(6)?,
+        amount,
+        lamports: mint.is_none().then_some(amount),
+        mint,
+        signature: row.get(9)?,
+        status: status_column(row, 10)?,
+        error: row.get(11)?,
+    })
+}
+
+fn order_from_row(row: &Row) -> rusqlite::Result<Order> {
+    Ok(Order {
+        id: row.get(0)?,
+        created_at: row.get(1)?,
+        buyer: row.get(2)?,
+        signature: row.get(3)?,
+        status: status_column(row, 4)?,
+        error: row.get(5)?,
+        reference: row.get(6)?,
+        memo: row.get(7)?,
+        url: row.get(8)?,
+        receipt: Receipt {
+            commitment: commitment_column(row, 9)?,
+            slot: row.get(10)?,
+            fee: row.get(11)?,
+        },
+        items: Vec::new(),
+    })
+}
+
+fn with_items(conn: &Connection, mut order: Order) -> Result<Order> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {PURCHASE_COLUMNS} FROM purchases WHERE order_id = ?1 ORDER BY id"
+    ))?;
+    order.items = stmt
+        .query_map([order.id], purchase_from_row)?
+        .collect::<Result<_, _>>()?;
+    Ok(order)
+}
+
+fn delivery_from_row(row: &Row) -> rusqlite::Result<Delivery> {
+    let status: String = row.get(6)?;
+    Ok(Delivery {
+        id: row.get(0)?,
+        created_at: row.get(1)?,
+        vendor: row.get(2)?,
+        event: row.get(3)?,
+        order_id: row.get(4)?,
+        payload: json_column(row, 5)?,
+        status: DeliveryStatus::parse(&status).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(
+                6,
+                rusqlite::types::Type::Text,
+                format!("unknown delivery status {status}").into(),
+            )
+        })?,
+        attempts: row.get(7)?,
+        next_attempt_at: row.get(8)?,
+        response_status: row.get(9)?,
+        error: row.get(10)?,
+    })
+}
+
+fn job_from_row(row: &Row) -> rusqlite::Result<Job> {
+    let status: String = row.get(6)?;
+    Ok(Job {
+        id: row.get(0)?,
+        created_at: row.get(1)?,
+        buyer: row.get(2)?,
+        signature: row.get(3)?,
+        service: row.get(4)?,
+        transaction: row.get(5)?,
+        status: JobStatus::parse(&status).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(
+                6,
+                rusqlite::types::Type::Text,
+                format!("unknown job status {status}").into(),
+            )
+        })?,
+        attempts: row.get(7)?,
+        next_attempt_at: row.get(8)?,
+        error: row.get(9)?,
+        order_id: row.get(10)?,
+    })
+}
+
+fn commitment_column(
+    row: &Row,
+    i: usize,
+) -> rusqlite::Result<Option<TransactionConfirmationStatus>> {
+    let Some(commitment) = row.get::<_, Option<String>>(i)? else {
+        return Ok(None);
+    };
+    serde_json::from_value(commitment.clone().into()).map_err(|_| {
+        rusqlite::Error::FromSqlConversionFailure(
+            i,
+            rusqlite::types::Type::Text,
+            format!("unknown commitment {commitment}").into(),
+        )
+    })
+}
+
+fn status_column(row: &Row, i: usize) -> rusqlite::Result<PurchaseStatus> {
+    let status: String = row.get(i)?;
+    PurchaseStatus::parse(&status).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            i,
+            rusqlite::types::Type::Text,
+            format!("unknown purchase status {status}").into(),
+        )
+    })
+}
+
+/// Whether an error is the violation of a unique constraint on `column`,
+/// given as `<table>.<column>`.
+fn is_unique_violation(error: &rusqlite::Error, column: &str) -> bool {
+    match error {
+        rusqlite::Error::SqliteFailure(e, Some(message)) => {
+            e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE && message.contains(column)
+        }
+        _ => false,
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("applying database migration {}", i + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)
+            .with_context(|| format!("database migration {} failed", i + 1))?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_databases_of_the_first_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute(
+            "INSERT INTO vendors (wallet_id, name, services) VALUES ('wallet', 'shop', '[\"coffee\"]')",
+            [],
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+        // migrating again is a no-op
+        migrate(&mut conn).unwrap();
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        let store = SqliteStore {
+            conn: Mutex::new(conn),
+        };
+        let vendor = store.get("wallet").unwrap().unwrap();
+        assert_eq!(vendor.status, VendorStatus::Approved);
+        assert!(vendor.accepted_mints.is_empty());
+        let [service] = &vendor.services[..] else {
+            panic!("expected a single service, got {:?}", vendor.services);
+        };
+        assert_eq!(
+            (service.id.as_str(), service.name.as_str()),
+            ("coffee", "coffee")
+        );
+        assert_eq!((service.price, service.active), (0, false));
+    }
+
+    #[test]
+    fn fails_duplicate_payments_when_migrating() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..11] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", 11).unwrap();
+        conn.execute_batch(
+            "INSERT INTO orders (id, created_at, buyer, signature, status)
+            VALUES (1, 0, 'a', 'sig', 'confirmed'), (2, 0, 'b', 'sig', 'pending'), (3, 0, 'c', '', 'pending');
+            INSERT INTO purchases (created_at, buyer, vendor, amount, signature, status, order_id)
+            VALUES (0, 'b', 'v', 1, 'sig', 'pending', 2);",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+        let store = SqliteStore {
+            conn: Mutex::new(conn),
+        };
+        let duplicate = store.get_order(2).unwrap().unwrap();
+        assert_eq!(duplicate.status, PurchaseStatus::Failed);
+        assert_eq!(
+            duplicate.error.as_deref(),
+            Some("transaction already pays order 1")
+        );
+        assert_eq!(duplicate.items[0].status, PurchaseStatus::Failed);
+        for id in [1, 3] {
+            let order = store.get_order(id).unwrap().unwrap();
+            assert_ne!(order.status, PurchaseStatus::Failed);
+        }
+        assert_eq!(store.find_order_by_signature("sig").unwrap().unwrap().id, 1);
+    }
+}
@@ -0,0 +1,396 @@
+use super::{add, prepare, SharedVendors, Vendor, VendorStatus};
+use crate::{auth::Admin, error::Error};
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, io::Write};
+use tracing::info;
+
+/// Separates the values of the `accepted_mints` column of csv files.
+const SEPARATOR: char = ';';
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Csv representation of a [`Vendor`], with a
+/// `wallet_id,name,address,services,accepted_mints,status` header. Only the
+/// wallet id and name columns are required, services are a json array.
+#[derive(Debug, Deserialize, Serialize)]
+struct CsvRow {
+    wallet_id: String,
+    name: String,
+    #[serde(default)]
+    address: String,
+    #[serde(default)]
+    services: String,
+    #[serde(default)]
+    accepted_mints: String,
+    #[serde(default)]
+    status: VendorStatus,
+}
+
+impl TryFrom<CsvRow> for Vendor {
+    type Error = serde_json::Error;
+
+    fn try_from(row: CsvRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            wallet_id: row.wallet_id,
+            name: row.name,
+            address: row.address,
+            services: if row.services.trim().is_empty() {
+                Vec::new()
+            } else {
+                serde_json::from_str(&row.services)?
+            },
+            accepted_mints: split(&row.accepted_mints),
+            status: row.status,
+        })
+    }
+}
+
+impl From<&Vendor> for CsvRow {
+    fn from(vendor: &Vendor) -> Self {
+        Self {
+            wallet_id: vendor.wallet_id.clone(),
+            name: vendor.name.clone(),
+            address: vendor.address.clone(),
+            services: serde_json::json!(vendor.services).to_string(),
+            accepted_mints: vendor.accepted_mints.join(&SEPARATOR.to_string()),
+            status: vendor.status,
+        }
+    }
+}
+
+fn split(column: &str) -> Vec<String> {
+    column
+        .split(SEPARATOR)
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(Into::into)
+        .collect()
+}
+
+/// Parses every row of a json array or csv file. Only a malformed document
+/// is an error, malformed rows are reported individually.
+pub fn parse(format: Format, data: &[u8]) -> Result<Vec<Result<Vendor, Error>>, Error> {
+    let invalid_row = |e: &dyn std::fmt::Display| Error::Validation(format!("invalid row: {e}"));
+    match format {
+        Format::Json => {
+            let rows: Vec<serde_json::Value> = serde_json::from_slice(data)
+                .map_err(|e| Error::Validation(format!("expected a json array: {e}")))?;
+            Ok(rows
+                .into_iter()
+                .map(|row| serde_json::from_value(row).map_err(|e| invalid_row(&e)))
+                .collect())
+        }
+        Format::Csv => Ok(csv::Reader::from_reader(data)
+            .deserialize::<CsvRow>()
+            .map(|row| {
+                let row = row.map_err(|e| invalid_row(&e))?;
+                Vendor::try_from(row).map_err(|e| invalid_row(&e))
+            })
+            .collect()),
+    }
+}
+
+pub fn write(format: Format, vendors: &[Vendor], writer: impl Write) -> Result<()> {
+    match format {
+        Format::Json => serde_json::to_writer_pretty(writer, vendors)?,
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for vendor in vendors {
+                writer.serialize(CsvRow::from(vendor))?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub failed: usize,
+    pub rows: Vec<RowResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RowResult {
+    /// 1-based index of the row in the imported document.
+    pub row: usize,
+    pub wallet_id: Option<String>,
+    pub imported: bool,
+    /// Error body, as sent by the api, of rows which could not be imported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+}
+
+/// Outcome of an imported row: the vendor and whether it was inserted, or the
+/// wallet id of the row, if it could be parsed, and why it was rejected.
+type Outcome = Result<(Vendor, bool), (Option<String>, Error)>;
+
+/// Whitelists every valid row. When `atomic`, nothing is imported unless every
+/// row is valid and none of them already exists.
+pub fn import(
+    vendors: &SharedVendors,
+    rows: Vec<Result<Vendor, Error>>,
+    atomic: bool,
+) -> Result<ImportReport, Error> {
+    let outcomes = if atomic {
+        import_atomic(vendors, rows)?
+    } else {
+        rows.into_iter()
+            .map(|row| check(row, |vendor| add(vendors, vendor)).map(|vendor| (vendor, true)))
+            .collect()
+    };
+
+    let rows: Vec<_> = outcomes
+        .into_iter()
+        .enumerate()
+        .map(|(i, outcome)| match outcome {
+            Ok((vendor, imported)) => RowResult {
+                row: i + 1,
+                wallet_id: Some(vendor.wallet_id),
+                imported,
+                error: None,
+            },
+            Err((wallet_id, e)) => RowResult {
+                row: i + 1,
+                wallet_id,
+                imported: false,
+                error: Some(e.body()),
+            },
+        })
+        .collect();
+    Ok(ImportReport {
+        imported: rows.iter().filter(|r| r.imported).count(),
+        failed: rows.iter().filter(|r| r.error.is_some()).count(),
+        rows,
+    })
+}
+
+fn import_atomic(
+    vendors: &SharedVendors,
+    rows: Vec<Result<Vendor, Error>>,
+) -> Result<Vec<Outcome>, Error> {
+    let mut seen = HashSet::new();
+    let prepared: Vec<_> = rows
+        .into_iter()
+        .map(|row| {
+            check(row, |vendor| {
+                let vendor = prepare(vendor)?;
+                if !seen.insert(vendor.wallet_id.clone()) {
+                    return Err(Error::Conflict(format!(
+                        "vendor {} appears more than once",
+                        vendor.wallet_id
+                    )));
+                }
+                Ok(vendor)
+            })
+        })
+        .collect();
+    if !prepared.iter().all(Result::is_ok) {
+        return Ok(prepared
+            .into_iter()
+            .map(|row| row.map(|vendor| (vendor, false)))
+            .collect());
+    }
+
+    let valid: Vec<_> = prepared.into_iter().flatten().collect();
+    let existing = vendors.insert_all(&valid)?;
+    Ok(valid
+        .into_iter()
+        .map(|vendor| {
+            if existing.contains(&vendor.wallet_id) {
+                let e = Error::Conflict(format!("vendor {} already exists", vendor.wallet_id));
+                Err((Some(vendor.wallet_id), e))
+            } else {
+                Ok((vendor, existing.is_empty()))
+            }
+        })
+        .collect())
+}
+
+fn check(
+    row: Result<Vendor, Error>,
+    f: impl FnOnce(Vendor) -> Result<Vendor, Error>,
+) -> Result<Vendor, (Option<String>, Error)> {
+    let vendor = row.map_err(|e| (None, e))?;
+    let wallet_id = vendor.wallet_id.clone();
+    f(vendor).map_err(|e| (Some(wallet_id), e))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportParams {
+    /// Defaults to csv if the content type is `text/csv`, json otherwise.
+    format: Option<Format>,
+    #[serde(default)]
+    atomic: bool,
+}
+
+/// Answers `422 Unprocessable Entity` if an atomic import was rejected.
+#[tracing::instrument(skip(vendors, headers, body))]
+pub async fn import_handler(
+    _: Admin,
+    State(vendors): State<SharedVendors>,
+    Query(params): Query<ImportParams>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<ImportReport>), Error> {
+    info!("importing vendors");
+    let format = params.format.unwrap_or_else(|| {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if content_type.starts_with("text/csv") {
+            Format::Csv
+        } else {
+            Format::Json
+        }
+    });
+    let report = import(&vendors, parse(format, &body)?, params.atomic)?;
+    let status = if params.atomic && report.failed > 0 {
+        StatusCode::UNPROCESSABLE_ENTITY
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(report)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    format: Format,
+}
+
+#[tracing::instrument(skip(vendors))]
+pub async fn export_handler(
+    _: Admin,
+    State(vendors): State<SharedVendors>,
+    Query(params): Query<ExportParams>,
+) -> Result<Response, Error> {
+    info!("exporting vendors");
+    let mut body = Vec::new();
+    write(params.format, &vendors.list()?, &mut body)?;
+    let (content_type, filename) = match params.format {
+        Format::Json => ("application/json", "vendors.json"),
+        Format::Csv => ("text/csv", "vendors.csv"),
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{filename}\""),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use solana_sdk::pubkey::Pubkey;
+    use std::sync::Arc;
+
+    #[test]
+    fn parses_csv_and_json_rows_individually() {
+        let wallet = Pubkey::new_unique().to_string();
+        let csv = format!(
+            "wallet_id,name,address,services,accepted_mints,status\n\
+            {wallet},Café,,\"[{{\"\"id\"\":\"\"coffee\"\",\"\"name\"\":\"\"Coffee\"\",\"\"price\"\":5000}}]\",a; b,pending\n\
+            {wallet},Other,,not json,,\n"
+        );
+        let rows = parse(Format::Csv, csv.as_bytes()).unwrap();
+        let [Ok(vendor), Err(Error::Validation(_))] = &rows[..] else {
+            panic!("expected a vendor and an invalid row, got {rows:?}");
+        };
+        assert_eq!(vendor.services[0].price, 5000);
+        assert_eq!(vendor.accepted_mints, ["a", "b"]);
+        assert_eq!(vendor.status, VendorStatus::Pending);
+
+        let mut written = Vec::new();
+        write(Format::Csv, std::slice::from_ref(vendor), &mut written).unwrap();
+        let rows = parse(Format::Csv, &written).unwrap();
+        let [Ok(read)] = &rows[..] else {
+            panic!("expected a vendor, got {rows:?}");
+        };
+        assert_eq!(read.services[0].id, "coffee");
+        assert_eq!(read.accepted_mints, vendor.accepted_mints);
+
+        let json = format!(r#"[{{"wallet_id": "{wallet}", "name": "Café"}}, {{"name": 1}}]"#);
+        let rows = parse(Format::Json, json.as_bytes()).unwrap();
+        assert!(matches!(&rows[..], [Ok(_), Err(Error::Validation(_))]));
+        assert!(parse(Format::Json, b"{}").is_err());
+    }
+
+    fn vendor(wallet_id: &str) -> Result<Vendor, Error> {
+        Ok(Vendor {
+            wallet_id: wallet_id.into(),
+            name: "Café".into(),
+            address: String::new(),
+            services: Vec::new(),
+            accepted_mints: Vec::new(),
+            status: VendorStatus::Approved,
+        })
+    }
+
+    #[test]
+    fn imports_valid_rows_and_reports_the_others() {
+        let vendors: SharedVendors = Arc::new(MemoryStore::default());
+        let wallet = Pubkey::new_unique().to_string();
+        let rows = vec![
+            vendor(&wallet),
+            vendor("not a pubkey"),
+            vendor(&wallet),
+            Err(Error::Validation("invalid row".into())),
+        ];
+        let report = import(&vendors, rows, false).unwrap();
+        assert_eq!((report.imported, report.failed), (1, 3));
+        let imported: Vec<_> = report.rows.iter().map(|row| row.imported).collect();
+        assert_eq!(imported, [true, false, false, false]);
+        assert_eq!(report.rows[2].wallet_id.as_deref(), Some(wallet.as_str()));
+        assert_eq!(report.rows[3].wallet_id, None);
+        assert_eq!(vendors.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn imports_nothing_atomically_unless_every_row_is_new_and_valid() {
+        let vendors: SharedVendors = Arc::new(MemoryStore::default());
+        let existing = Pubkey::new_unique().to_string();
+        vendors.insert(&vendor(&existing).unwrap()).unwrap();
+        let (first, second) = (
+            Pubkey::new_unique().to_string(),
+            Pubkey::new_unique().to_string(),
+        );
+
+        let invalid = vec![vendor(&first), vendor("not a pubkey")];
+        let duplicated = vec![vendor(&first), vendor(&first)];
+        let conflicting = vec![vendor(&first), vendor(&existing)];
+        for rows in [invalid, duplicated, conflicting] {
+            let report = import(&vendors, rows, true).unwrap();
+            assert_eq!((report.imported, report.failed), (0, 1));
+            assert_eq!(vendors.list().unwrap().len(), 1);
+        }
+
+        let report = import(&vendors, vec![vendor(&first), vendor(&second)], true).unwrap();
+        assert_eq!((report.imported, report.failed), (2, 0));
+        assert_eq!(vendors.list().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,98 @@
+use super::{find, save, Service, SharedVendors};
+use crate::{auth::Admin, error::Error};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+use tracing::info;
+
+/// Body of `PUT /vendors/:wallet_id/services/:service_id`, the id is taken
+/// from the path.
+#[derive(Debug, Deserialize)]
+pub struct ServiceFields {
+    name: String,
+    #[serde(default)]
+    description: String,
+    price: u64,
+    #[serde(default)]
+    mint: Option<String>,
+    #[serde(default = "super::active")]
+    active: bool,
+}
+
+/// Lists every service of a vendor, including inactive ones.
+#[tracing::instrument(skip(vendors))]
+pub async fn list(
+    State(vendors): State<SharedVendors>,
+    Path(wallet_id): Path<String>,
+) -> Result<Json<Vec<Service>>, Error> {
+    info!("retrieving services");
+    Ok(Json(find(&vendors, &wallet_id)?.services))
+}
+
+#[tracing::instrument(skip(vendors))]
+pub async fn fetch(
+    State(vendors): State<SharedVendors>,
+    Path((wallet_id, service_id)): Path<(String, String)>,
+) -> Result<Json<Service>, Error> {
+    info!("retrieving service");
+    find(&vendors, &wallet_id)?
+        .services
+        .into_iter()
+        .find(|service| service.id == service_id)
+        .map(Json)
+        .ok_or_else(|| not_found(&wallet_id, &service_id))
+}
+
+/// Adds a service to the catalog, or replaces the one with the same id.
+#[tracing::instrument(skip(vendors))]
+pub async fn save_handler(
+    _: Admin,
+    State(vendors): State<SharedVendors>,
+    Path((wallet_id, service_id)): Path<(String, String)>,
+    Json(input): Json<ServiceFields>,
+) -> Result<Json<Service>, Error> {
+    info!("saving service");
+    let mut vendor = find(&vendors, &wallet_id)?;
+    let service = Service {
+        id: service_id.clone(),
+        name: input.name,
+        description: input.description,
+        price: input.price,
+        mint: input.mint,
+        active: input.active,
+    };
+    match vendor.services.iter_mut().find(|s| s.id == service_id) {
+        Some(existing) => *existing = service,
+        None => vendor.services.push(service),
+    }
+    save(&vendors, vendor)?
+        .services
+        .into_iter()
+        .find(|service| service.id == service_id)
+        .map(Json)
+        .ok_or_else(|| not_found(&wallet_id, &service_id))
+}
+
+#[tracing::instrument(skip(vendors))]
+pub async fn remove(
+    _: Admin,
+    State(vendors): State<SharedVendors>,
+    Path((wallet_id, service_id)): Path<(String, String)>,
+) -> Result<StatusCode, Error> {
+    info!("removing service");
+    let mut vendor = find(&vendors, &wallet_id)?;
+    let len = vendor.services.len();
+    vendor.services.retain(|service| service.id != service_id);
+    if vendor.services.len() == len {
+        return Err(not_found(&wallet_id, &service_id));
+    }
+    save(&vendors, vendor)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn not_found(wallet_id: &str, service_id: &str) -> Error {
+    Error::NotFound(format!("vendor {wallet_id} has no service {service_id}"))
+}
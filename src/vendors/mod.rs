@@ -0,0 +1,530 @@
+use crate::{
+    auth::{Admin, Role, SharedAuth},
+    error::Error,
+    store::VendorStore,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::{collections::HashSet, fmt, str::FromStr, sync::Arc};
+use tracing::info;
+
+pub mod bulk;
+mod catalog;
+mod onboarding;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Vendor {
+    pub wallet_id: String,
+    pub name: String,
+    #[serde(default)]
+    pub address: String,
+    #[serde(default)]
+    pub services: Vec<Service>,
+    /// Spl token mints the vendor can be paid in, besides native sol.
+    #[serde(default)]
+    pub accepted_mints: Vec<String>,
+    /// Vendors added by an admin are approved unless told otherwise.
+    #[serde(default)]
+    pub status: VendorStatus,
+}
+
+impl Vendor {
+    /// Looks up a service which can currently be purchased.
+    pub fn service(&self, id: &str) -> Result<&Service, Error> {
+        self.services
+            .iter()
+            .find(|service| service.id == id && service.active)
+            .ok_or_else(|| {
+                Error::NotFound(format!(
+                    "vendor {} has no service {id} for sale",
+                    self.wallet_id
+                ))
+            })
+    }
+}
+
+/// Entry of a vendor's catalog, purchases reference it by id.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Service {
+    /// Unique among the services of the vendor.
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// In lamports, or in base units of `mint`.
+    pub price: u64,
+    /// Spl token the price is in, one of the vendor's accepted mints, native
+    /// sol if absent.
+    #[serde(default)]
+    pub mint: Option<String>,
+    /// Inactive services are listed but can not be purchased.
+    #[serde(default = "active")]
+    pub active: bool,
+}
+
+fn active() -> bool {
+    true
+}
+
+/// `<ID>=<PRICE>` or `<ID>=<PRICE>@<MINT>`, naming the service after its id.
+impl FromStr for Service {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        let (id, price) = value
+            .split_once('=')
+            .ok_or_else(|| format!("expected <ID>=<PRICE>[@<MINT>], got {value}"))?;
+        let (price, mint) = match price.split_once('@') {
+            Some((price, mint)) => (price, Some(mint.to_string())),
+            None => (price, None),
+        };
+        Ok(Self {
+            id: id.into(),
+            name: id.into(),
+            description: String::new(),
+            price: price
+                .parse()
+                .map_err(|e| format!("invalid price {price}: {e}"))?,
+            mint,
+            active: true,
+        })
+    }
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.id, self.price)?;
+        if let Some(mint) = &self.mint {
+            write!(f, "@{mint}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Only approved vendors are whitelisted, applications start pending.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VendorStatus {
+    Pending,
+    #[default]
+    Approved,
+    Rejected,
+}
+
+impl VendorStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Approved => "approved",
+            Self::Rejected => "rejected",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(Self::Pending),
+            "approved" => Some(Self::Approved),
+            "rejected" => Some(Self::Rejected),
+            _ => None,
+        }
+    }
+}
+
+/// Body of `PUT /vendors/:wallet_id`, the wallet id is taken from the path.
+#[derive(Debug, Deserialize)]
+pub struct VendorFields {
+    name: String,
+    #[serde(default)]
+    address: String,
+    #[serde(default)]
+    services: Vec<Service>,
+    #[serde(default)]
+    accepted_mints: Vec<String>,
+}
+
+/// Body of `PATCH /vendors/:wallet_id`, absent fields are left untouched.
+#[derive(Debug, Deserialize)]
+pub struct VendorPatch {
+    name: Option<String>,
+    address: Option<String>,
+    services: Option<Vec<Service>>,
+    accepted_mints: Option<Vec<String>>,
+}
+
+pub type SharedVendors = Arc<dyn VendorStore>;
+
+pub fn router(vendors: SharedVendors) -> Router {
+    Router::new()
+        .route("/vendors", get(list).post(insert))
+        .route("/vendors/import", post(bulk::import_handler))
+        .route("/vendors/export", get(bulk::export_handler))
+        .route("/vendors/applications", post(onboarding::apply))
+        .route("/vendors/:wallet_id/approve", post(onboarding::approve))
+        .route("/vendors/:wallet_id/reject", post(onboarding::reject))
+        .route("/vendors/:wallet_id/services", get(catalog::list))
+        .route(
+            "/vendors/:wallet_id/services/:service_id",
+            get(catalog::fetch)
+                .put(catalog::save_handler)
+                .delete(catalog::remove),
+        )
+        .route(
+            "/vendors/:wallet_id",
+            get(fetch).put(replace).patch(update).delete(remove),
+        )
+        .with_state(vendors)
+}
+
+#[derive(Debug, Deserialize)]
+struct ListParams {
+    #[serde(default)]
+    status: VendorStatus,
+}
+
+/// Lists the approved vendors, or those with the requested `status` for
+/// admins, since applicants are not public.
+#[tracing::instrument(skip(auth, headers, vendors))]
+async fn list(
+    Extension(auth): Extension<SharedAuth>,
+    headers: HeaderMap,
+    State(vendors): State<SharedVendors>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<Vec<Vendor>>, Error> {
+    info!("retrieving all vendors");
+    if params.status != VendorStatus::Approved {
+        auth.authorize(&headers, Role::Admin)?;
+    }
+    let mut list = vendors.list()?;
+    list.retain(|vendor| vendor.status == params.status);
+    Ok(Json(list))
+}
+
+#[tracing::instrument(skip(vendors))]
+async fn insert(
+    _: Admin,
+    State(vendors): State<SharedVendors>,
+    Json(input): Json<Vendor>,
+) -> Result<(), Error> {
+    info!("adding new vendor");
+    add(&vendors, input)?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(vendors))]
+async fn fetch(
+    State(vendors): State<SharedVendors>,
+    Path(wallet_id): Path<String>,
+) -> Result<Json<Vendor>, Error> {
+    info!("retrieving vendor");
+    find(&vendors, &wallet_id).map(Json)
+}
+
+#[tracing::instrument(skip(vendors))]
+async fn replace(
+    _: Admin,
+    State(vendors): State<SharedVendors>,
+    Path(wallet_id): Path<String>,
+    Json(input): Json<VendorFields>,
+) -> Result<Json<Vendor>, Error> {
+    info!("replacing vendor");
+    let status = find(&vendors, &wallet_id)?.status;
+    let vendor = Vendor {
+        wallet_id,
+        name: input.name,
+        address: input.address,
+        services: input.services,
+        accepted_mints: input.accepted_mints,
+        status,
+    };
+    save(&vendors, vendor).map(Json)
+}
+
+#[tracing::instrument(skip(vendors))]
+async fn update(
+    _: Admin,
+    State(vendors): State<SharedVendors>,
+    Path(wallet_id): Path<String>,
+    Json(patch): Json<VendorPatch>,
+) -> Result<Json<Vendor>, Error> {
+    info!("updating vendor");
+    let mut vendor = find(&vendors, &wallet_id)?;
+    if let Some(name) = patch.name {
+        vendor.name = name;
+    }
+    if let Some(address) = patch.address {
+        vendor.address = address;
+    }
+    if let Some(services) = patch.services {
+        vendor.services = services;
+    }
+    if let Some(accepted_mints) = patch.accepted_mints {
+        vendor.accepted_mints = accepted_mints;
+    }
+    save(&vendors, vendor).map(Json)
+}
+
+#[tracing::instrument(skip(vendors))]
+async fn remove(
+    _: Admin,
+    State(vendors): State<SharedVendors>,
+    Path(wallet_id): Path<String>,
+) -> Result<StatusCode, Error> {
+    info!("removing vendor");
+    delete(&vendors, &wallet_id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Whitelists a new vendor, once its wallet id is checked to be a valid pubkey.
+pub fn add(vendors: &SharedVendors, vendor: Vendor) -> Result<Vendor, Error> {
+    let vendor = prepare(vendor)?;
+    if !vendors.insert(&vendor)? {
+        return Err(Error::Conflict(format!(
+            "vendor {} already exists",
+            vendor.wallet_id
+        )));
+    }
+    Ok(vendor)
+}
+
+pub fn delete(vendors: &SharedVendors, wallet_id: &str) -> Result<(), Error> {
+    if !vendors.delete(wallet_id)? {
+        return Err(not_found(wallet_id));
+    }
+    Ok(())
+}
+
+pub fn set_status(
+    vendors: &SharedVendors,
+    wallet_id: &str,
+    status: VendorStatus,
+) -> Result<Vendor, Error> {
+    if !vendors.set_status(wallet_id, status)? {
+        return Err(not_found(wallet_id));
+    }
+    find(vendors, wallet_id)
+}
+
+fn find(vendors: &SharedVendors, wallet_id: &str) -> Result<Vendor, Error> {
+    vendors.get(wallet_id)?.ok_or_else(|| not_found(wallet_id))
+}
+
+fn save(vendors: &SharedVendors, mut vendor: Vendor) -> Result<Vendor, Error> {
+    validate(&mut vendor)?;
+    if !vendors.update(&vendor)? {
+        return Err(not_found(&vendor.wallet_id));
+    }
+    Ok(vendor)
+}
+
+/// Validates a vendor about to be inserted, normalizing its wallet id.
+fn prepare(mut vendor: Vendor) -> Result<Vendor, Error> {
+    vendor.wallet_id = normalize("wallet_id", &vendor.wallet_id)?;
+    validate(&mut vendor)?;
+    Ok(vendor)
+}
+
+/// Validates the fields of a vendor, normalizing its mints.
+fn validate(vendor: &mut Vendor) -> Result<(), Error> {
+    if vendor.name.trim().is_empty() {
+        return Err(Error::Validation("vendor name must not be empty".into()));
+    }
+    vendor.accepted_mints = vendor
+        .accepted_mints
+        .iter()
+        .map(|mint| normalize("mint", mint))
+        .collect::<Result<_, _>>()?;
+    let mut ids = HashSet::new();
+    for service in &mut vendor.services {
+        if service.id.trim().is_empty() || service.name.trim().is_empty() {
+            return Err(Error::Validation(
+                "service id and name must not be empty".into(),
+            ));
+        }
+        if !ids.insert(service.id.clone()) {
+            return Err(Error::Validation(format!(
+                "service {} appears more than once",
+                service.id
+            )));
+        }
+        if let Some(mint) = &service.mint {
+            let mint = normalize("mint", mint)?;
+            if !vendor.accepted_mints.contains(&mint) {
+                return Err(Error::Validation(format!(
+                    "service {} is priced in {mint}, which is not an accepted mint",
+                    service.id
+                )));
+            }
+            service.mint = Some(mint);
+        }
+    }
+    Ok(())
+}
+
+fn normalize(field: &str, pubkey: &str) -> Result<String, Error> {
+    Pubkey::from_str(pubkey)
+        .map(|pubkey| pubkey.to_string())
+        .map_err(|e| Error::Validation(format!("invalid {field} {pubkey}: {e}")))
+}
+
+fn not_found(wallet_id: &str) -> Error {
+    Error::NotFound(format!("vendor {wallet_id} not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{self, Auth, API_KEY_HEADER},
+        store::MemoryStore,
+    };
+    use axum::Server;
+    use serde_json::json;
+
+    const ADMIN_TOKEN: &str = "admin-token";
+
+    fn vendor(wallet_id: &str) -> Vendor {
+        Vendor {
+            wallet_id: wallet_id.into(),
+            name: "Café".into(),
+            address: String::new(),
+            services: Vec::new(),
+            accepted_mints: Vec::new(),
+            status: VendorStatus::Approved,
+        }
+    }
+
+    fn service(id: &str, mint: Option<&str>) -> Service {
+        Service {
+            id: id.into(),
+            name: "Coffee".into(),
+            description: String::new(),
+            price: 5000,
+            mint: mint.map(Into::into),
+            active: true,
+        }
+    }
+
+    #[test]
+    fn validates_vendors() {
+        let (wallet, mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let invalid = |vendor| matches!(prepare(vendor), Err(Error::Validation(_)));
+        assert!(invalid(vendor("not a pubkey")));
+        assert!(invalid(Vendor {
+            name: " ".into(),
+            ..vendor(&wallet.to_string())
+        }));
+        assert!(invalid(Vendor {
+            accepted_mints: vec!["not a pubkey".into()],
+            ..vendor(&wallet.to_string())
+        }));
+        assert!(invalid(Vendor {
+            services: vec![service("coffee", None), service("coffee", None)],
+            ..vendor(&wallet.to_string())
+        }));
+        assert!(invalid(Vendor {
+            services: vec![service("", None)],
+            ..vendor(&wallet.to_string())
+        }));
+        assert!(invalid(Vendor {
+            services: vec![service("coffee", Some(&mint.to_string()))],
+            ..vendor(&wallet.to_string())
+        }));
+
+        let vendor = prepare(Vendor {
+            services: vec![service("coffee", Some(&mint.to_string()))],
+            accepted_mints: vec![mint.to_string()],
+            ..vendor(&wallet.to_string())
+        })
+        .unwrap();
+        assert_eq!(vendor.wallet_id, wallet.to_string());
+        assert_eq!(vendor.services[0].mint, Some(mint.to_string()));
+    }
+
+    #[tokio::test]
+    async fn answers_vendor_requests_with_their_status_codes() {
+        let auth = Auth::new(
+            &[auth::hash(ADMIN_TOKEN)],
+            &[auth::hash("client-token")],
+            false,
+        )
+        .unwrap();
+        let router = router(Arc::new(MemoryStore::default())).layer(Extension(Arc::new(auth)));
+        let server =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(router.into_make_service());
+        let url = format!("http://{}/vendors", server.local_addr());
+        tokio::spawn(server);
+        let client = reqwest::Client::new();
+        let wallet = Pubkey::new_unique().to_string();
+        let vendor_url = format!("{url}/{wallet}");
+        let status = |request: reqwest::RequestBuilder| async move {
+            request
+                .header(API_KEY_HEADER, ADMIN_TOKEN)
+                .send()
+                .await
+                .unwrap()
+                .status()
+        };
+
+        let body = json!({ "wallet_id": wallet, "name": "Café" });
+        let response = client.post(&url).json(&body).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(client.post(&url).json(&body)).await, StatusCode::OK);
+        assert_eq!(
+            status(client.post(&url).json(&body)).await,
+            StatusCode::CONFLICT
+        );
+        let invalid = json!({ "wallet_id": "not a pubkey", "name": "Café" });
+        assert_eq!(
+            status(client.post(&url).json(&invalid)).await,
+            StatusCode::BAD_REQUEST
+        );
+
+        let response = client.get(&vendor_url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let unknown = format!("{url}/{}", Pubkey::new_unique());
+        let response = client.get(&unknown).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let replaced = json!({ "name": "Bar", "address": "1 rue de Paris" });
+        assert_eq!(
+            status(client.put(&vendor_url).json(&replaced)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(client.put(&unknown).json(&replaced)).await,
+            StatusCode::NOT_FOUND
+        );
+        let patch = json!({ "name": "Café" });
+        assert_eq!(
+            status(client.patch(&vendor_url).json(&patch)).await,
+            StatusCode::OK
+        );
+        let vendor: Vendor = client
+            .get(&vendor_url)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(
+            (vendor.name.as_str(), vendor.address.as_str()),
+            ("Café", "1 rue de Paris")
+        );
+
+        assert_eq!(
+            status(client.delete(&vendor_url)).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            status(client.delete(&vendor_url)).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
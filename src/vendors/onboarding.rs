@@ -0,0 +1,255 @@
+use super::{find, prepare, set_status, Service, SharedVendors, Vendor, VendorStatus};
+use crate::{auth::Admin, error::Error, purchases};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+use std::str::FromStr;
+use tracing::info;
+
+/// Seconds an application stays valid after it is signed, either way to
+/// allow for clock skew, so a captured one can not be replayed later.
+const MAX_AGE: i64 = 300;
+
+/// Applications waiting for an admin at once, since anyone can apply: new
+/// ones are refused beyond it until some are approved or rejected.
+pub const MAX_PENDING: usize = 1000;
+
+/// Body of `POST /vendors/applications`.
+#[derive(Debug, Deserialize)]
+pub struct Application {
+    wallet_id: String,
+    name: String,
+    #[serde(default)]
+    address: String,
+    #[serde(default)]
+    services: Vec<Service>,
+    #[serde(default)]
+    accepted_mints: Vec<String>,
+    /// Unix timestamp, in seconds, at which the application was signed.
+    signed_at: i64,
+    /// Base58 encoded ed25519 signature of [`message`] by the `wallet_id` key.
+    signature: String,
+}
+
+/// The text applicants sign with their wallet: a header line then the
+/// canonical json of every field but the signature, absent ones taking their
+/// default, so an application can not be altered once signed.
+fn message(application: &Application) -> String {
+    let fields = json!({
+        "wallet_id": application.wallet_id,
+        "name": application.name,
+        "address": application.address,
+        "services": application.services,
+        "accepted_mints": application.accepted_mints,
+        "signed_at": application.signed_at,
+    });
+    format!(
+        "Apply to be whitelisted as a vendor.\n\n{}",
+        canonical(&fields)
+    )
+}
+
+/// Compact json with the keys of every object sorted.
+fn canonical(value: &Value) -> String {
+    match value {
+        Value::Object(fields) => {
+            let mut fields: Vec<_> = fields.iter().collect();
+            fields.sort_by_key(|(key, _)| *key);
+            let fields: Vec<_> = fields
+                .into_iter()
+                .map(|(key, value)| format!("{}:{}", Value::from(key.as_str()), canonical(value)))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        }
+        Value::Array(items) => {
+            let items: Vec<_> = items.iter().map(canonical).collect();
+            format!("[{}]", items.join(","))
+        }
+        value => value.to_string(),
+    }
+}
+
+/// Records a pending vendor once the application is proven to be recently
+/// signed by its wallet. Rejected vendors may apply again, others are a
+/// conflict, as is any application once [`MAX_PENDING`] are waiting.
+#[tracing::instrument(skip(vendors))]
+pub async fn apply(
+    State(vendors): State<SharedVendors>,
+    Json(application): Json<Application>,
+) -> Result<(StatusCode, Json<Vendor>), Error> {
+    info!("receiving vendor application");
+    let wallet = Pubkey::from_str(&application.wallet_id).map_err(|e| {
+        Error::Validation(format!("invalid wallet_id {}: {e}", application.wallet_id))
+    })?;
+    let signature = Signature::from_str(&application.signature)
+        .map_err(|e| Error::Validation(format!("invalid signature: {e}")))?;
+    if !signature.verify(wallet.as_ref(), message(&application).as_bytes()) {
+        return Err(Error::Unauthorized(
+            "application is not signed by the wallet_id key".into(),
+        ));
+    }
+    if (purchases::now() - application.signed_at).abs() > MAX_AGE {
+        return Err(Error::Validation(format!(
+            "application must be signed within {MAX_AGE} seconds, sign it again"
+        )));
+    }
+
+    let vendor = prepare(Vendor {
+        wallet_id: application.wallet_id,
+        name: application.name,
+        address: application.address,
+        services: application.services,
+        accepted_mints: application.accepted_mints,
+        status: VendorStatus::Pending,
+    })?;
+    let pending = vendors
+        .list()?
+        .iter()
+        .filter(|vendor| vendor.status == VendorStatus::Pending)
+        .count();
+    if pending >= MAX_PENDING {
+        return Err(Error::Conflict(format!(
+            "{pending} applications are already pending, apply again later"
+        )));
+    }
+    if !vendors.insert(&vendor)? && !vendors.reapply(&vendor)? {
+        let existing = find(&vendors, &vendor.wallet_id)?;
+        return Err(Error::Conflict(format!(
+            "vendor {} is already {}",
+            vendor.wallet_id,
+            existing.status.as_str()
+        )));
+    }
+    Ok((StatusCode::ACCEPTED, Json(vendor)))
+}
+
+#[tracing::instrument(skip(vendors))]
+pub async fn approve(
+    _: Admin,
+    State(vendors): State<SharedVendors>,
+    Path(wallet_id): Path<String>,
+) -> Result<Json<Vendor>, Error> {
+    info!("approving vendor");
+    set_status(&vendors, &wallet_id, VendorStatus::Approved).map(Json)
+}
+
+/// Rejects an application, or revokes an approved vendor.
+#[tracing::instrument(skip(vendors))]
+pub async fn reject(
+    _: Admin,
+    State(vendors): State<SharedVendors>,
+    Path(wallet_id): Path<String>,
+) -> Result<Json<Vendor>, Error> {
+    info!("rejecting vendor");
+    set_status(&vendors, &wallet_id, VendorStatus::Rejected).map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use solana_sdk::signature::{Keypair, Signer};
+    use std::sync::Arc;
+
+    fn application(keypair: &Keypair) -> Application {
+        serde_json::from_value(json!({
+            "wallet_id": keypair.pubkey().to_string(),
+            "name": "Café",
+            "services": [{ "id": "coffee", "name": "Coffee", "price": 5000 }],
+            "signed_at": 1700000000,
+            "signature": "",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn signs_every_field_canonically() {
+        let keypair = Keypair::new();
+        let application = application(&keypair);
+        assert_eq!(
+            message(&application),
+            format!(
+                "Apply to be whitelisted as a vendor.\n\n\
+                {{\"accepted_mints\":[],\"address\":\"\",\"name\":\"Café\",\"services\":[\
+                {{\"active\":true,\"description\":\"\",\"id\":\"coffee\",\"mint\":null,\
+                \"name\":\"Coffee\",\"price\":5000}}],\"signed_at\":1700000000,\"wallet_id\":\"{}\"}}",
+                keypair.pubkey()
+            )
+        );
+
+        let signature = keypair.sign_message(message(&application).as_bytes());
+        let mut altered = self::application(&keypair);
+        altered.services[0].description = "decaf".into();
+        assert!(signature.verify(keypair.pubkey().as_ref(), message(&application).as_bytes()));
+        assert!(!signature.verify(keypair.pubkey().as_ref(), message(&altered).as_bytes()));
+    }
+
+    fn signed(keypair: &Keypair, signed_at: i64) -> Application {
+        let mut application = application(keypair);
+        application.signed_at = signed_at;
+        application.signature = keypair
+            .sign_message(message(&application).as_bytes())
+            .to_string();
+        application
+    }
+
+    #[tokio::test]
+    async fn refuses_stale_applications_and_reapplies_rejected_vendors() {
+        let vendors: SharedVendors = Arc::new(MemoryStore::default());
+        let keypair = Keypair::new();
+        let wallet_id = keypair.pubkey().to_string();
+        let submit = |application| apply(State(vendors.clone()), Json(application));
+
+        let stale = signed(&keypair, purchases::now() - MAX_AGE - 1);
+        assert!(matches!(submit(stale).await, Err(Error::Validation(_))));
+
+        let mut forged = signed(&keypair, purchases::now());
+        forged.name = "Other".into();
+        assert!(matches!(submit(forged).await, Err(Error::Unauthorized(_))));
+
+        assert!(submit(signed(&keypair, purchases::now())).await.is_ok());
+        assert!(matches!(
+            submit(signed(&keypair, purchases::now())).await,
+            Err(Error::Conflict(_))
+        ));
+
+        vendors
+            .set_status(&wallet_id, VendorStatus::Rejected)
+            .unwrap();
+        assert!(submit(signed(&keypair, purchases::now())).await.is_ok());
+        let vendor = vendors.get(&wallet_id).unwrap().unwrap();
+        assert_eq!(vendor.status, VendorStatus::Pending);
+    }
+
+    #[tokio::test]
+    async fn refuses_applications_beyond_the_pending_cap() {
+        let vendors: SharedVendors = Arc::new(MemoryStore::default());
+        for _ in 0..MAX_PENDING {
+            let pending = Vendor {
+                wallet_id: Pubkey::new_unique().to_string(),
+                name: "Pending".into(),
+                address: String::new(),
+                services: Vec::new(),
+                accepted_mints: Vec::new(),
+                status: VendorStatus::Pending,
+            };
+            vendors.insert(&pending).unwrap();
+        }
+        let submit = |application| apply(State(vendors.clone()), Json(application));
+
+        let keypair = Keypair::new();
+        assert!(matches!(
+            submit(signed(&keypair, purchases::now())).await,
+            Err(Error::Conflict(_))
+        ));
+
+        let first = vendors.list().unwrap()[0].wallet_id.clone();
+        vendors.set_status(&first, VendorStatus::Approved).unwrap();
+        assert!(submit(signed(&keypair, purchases::now())).await.is_ok());
+    }
+}
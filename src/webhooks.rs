@@ -0,0 +1,694 @@
+use crate::{
+    auth::{self, Admin},
+    error::Error,
+    purchases::{self, Order, PurchaseStatus},
+    retry,
+    store::WebhookStore,
+    vendors::SharedVendors,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header::CONTENT_TYPE, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use futures::{stream, StreamExt};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use std::{sync::Arc, time::Duration};
+use tracing::{info, warn};
+
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 500;
+/// Deliveries attempted per poll of the queue.
+const BATCH: u32 = 20;
+/// Deliveries attempted at once to the same vendor, so a slow endpoint only
+/// holds up a poll for one timeout.
+const PER_VENDOR: u32 = 2;
+
+/// Endpoint a vendor is notified at when one of its purchases settles.
+#[derive(Debug, Clone, Serialize)]
+pub struct Webhook {
+    pub vendor: String,
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature of every event, only returned when
+    /// the webhook is registered.
+    #[serde(skip_serializing)]
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    /// Gave up after the maximum number of attempts, until retried by an admin.
+    Dead,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Delivered => "delivered",
+            Self::Dead => "dead",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(Self::Pending),
+            "delivered" => Some(Self::Delivered),
+            "dead" => Some(Self::Dead),
+            _ => None,
+        }
+    }
+}
+
+/// An event queued for a vendor's webhook, and the outcome of its attempts.
+#[derive(Debug, Clone, Serialize)]
+pub struct Delivery {
+    pub id: u64,
+    pub created_at: i64,
+    pub vendor: String,
+    /// `purchase.confirmed` or `purchase.failed`.
+    pub event: String,
+    pub order_id: u64,
+    /// Body posted to the webhook.
+    pub payload: serde_json::Value,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    /// Unix timestamp, in seconds, of the next attempt while pending.
+    pub next_attempt_at: i64,
+    /// Status code of the last response, absent if none was received.
+    pub response_status: Option<u16>,
+    /// Why the last attempt failed.
+    pub error: Option<String>,
+}
+
+/// Query of `GET /webhooks/deliveries`.
+#[derive(Debug, Default, Deserialize)]
+pub struct DeliveryFilter {
+    pub vendor: Option<String>,
+    pub status: Option<DeliveryStatus>,
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: u32,
+}
+
+impl DeliveryFilter {
+    pub fn limit(&self) -> u32 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT)
+    }
+
+    pub fn matches(&self, delivery: &Delivery) -> bool {
+        self.vendor.as_ref().is_none_or(|v| *v == delivery.vendor)
+            && self.status.is_none_or(|s| s == delivery.status)
+    }
+}
+
+/// How failed deliveries are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct Retries {
+    /// Attempts before a delivery is dead.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled after every failed one.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Time the receiver has to answer an attempt.
+    pub timeout: Duration,
+}
+
+impl Default for Retries {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(3600),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+pub type SharedWebhooks = Arc<dyn WebhookStore>;
+
+#[derive(Clone)]
+pub struct WebhookState {
+    pub vendors: SharedVendors,
+    pub webhooks: SharedWebhooks,
+}
+
+pub fn router(state: WebhookState) -> Router {
+    Router::new()
+        .route(
+            "/vendors/:wallet_id/webhook",
+            get(fetch).put(register).delete(remove),
+        )
+        .route("/webhooks/deliveries", get(deliveries))
+        .route("/webhooks/deliveries/:id/retry", post(retry))
+        .with_state(state)
+}
+
+/// Body of `PUT /vendors/:wallet_id/webhook`.
+#[derive(Debug, Deserialize)]
+pub struct WebhookParams {
+    url: String,
+    /// Generated if absent.
+    secret: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Registration {
+    vendor: String,
+    url: String,
+    secret: String,
+}
+
+/// Registers, or replaces, the webhook of a vendor. The secret is only ever
+/// returned here.
+#[tracing::instrument(skip(state, params))]
+async fn register(
+    _: Admin,
+    State(state): State<WebhookState>,
+    Path(wallet_id): Path<String>,
+    Json(params): Json<WebhookParams>,
+) -> Result<Json<Registration>, Error> {
+    info!("registering webhook");
+    if state.vendors.get(&wallet_id)?.is_none() {
+        return Err(Error::NotFound(format!("vendor {wallet_id} not found")));
+    }
+    match reqwest::Url::parse(&params.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+        _ => {
+            return Err(Error::Validation(format!(
+                "webhook url {} must be an http or https url",
+                params.url
+            )))
+        }
+    }
+    let webhook = Webhook {
+        vendor: wallet_id,
+        url: params.url,
+        secret: params.secret.unwrap_or_else(auth::random_token),
+    };
+    if webhook.secret.is_empty() {
+        return Err(Error::Validation("webhook secret must not be empty".into()));
+    }
+    state.webhooks.set_webhook(&webhook)?;
+    Ok(Json(Registration {
+        vendor: webhook.vendor,
+        url: webhook.url,
+        secret: webhook.secret,
+    }))
+}
+
+#[tracing::instrument(skip(state))]
+async fn fetch(
+    _: Admin,
+    State(state): State<WebhookState>,
+    Path(wallet_id): Path<String>,
+) -> Result<Json<Webhook>, Error> {
+    info!("retrieving webhook");
+    state
+        .webhooks
+        .get_webhook(&wallet_id)?
+        .map(Json)
+        .ok_or_else(|| not_found(&wallet_id))
+}
+
+#[tracing::instrument(skip(state))]
+async fn remove(
+    _: Admin,
+    State(state): State<WebhookState>,
+    Path(wallet_id): Path<String>,
+) -> Result<StatusCode, Error> {
+    info!("removing webhook");
+    if !state.webhooks.delete_webhook(&wallet_id)? {
+        return Err(not_found(&wallet_id));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lists deliveries, newest first. Dead ones form the dead letter queue.
+#[tracing::instrument(skip(state))]
+async fn deliveries(
+    _: Admin,
+    State(state): State<WebhookState>,
+    Query(filter): Query<DeliveryFilter>,
+) -> Result<Json<Vec<Delivery>>, Error> {
+    info!("retrieving webhook deliveries");
+    Ok(Json(state.webhooks.list_deliveries(&filter)?))
+}
+
+/// Queues a dead delivery again, with a fresh number of attempts.
+#[tracing::instrument(skip(state))]
+async fn retry(
+    _: Admin,
+    State(state): State<WebhookState>,
+    Path(id): Path<u64>,
+) -> Result<Json<Delivery>, Error> {
+    info!("retrying webhook delivery");
+    let mut delivery = state
+        .webhooks
+        .get_delivery(id)?
+        .ok_or_else(|| Error::NotFound(format!("delivery {id} not found")))?;
+    if delivery.status != DeliveryStatus::Dead {
+        return Err(Error::Conflict(format!(
+            "delivery {id} is {}, only dead ones can be retried",
+            delivery.status.as_str()
+        )));
+    }
+    delivery.status = DeliveryStatus::Pending;
+    delivery.attempts = 0;
+    delivery.next_attempt_at = purchases::now();
+    state.webhooks.update_delivery(&delivery)?;
+    Ok(Json(delivery))
+}
+
+fn not_found(wallet_id: &str) -> Error {
+    Error::NotFound(format!("vendor {wallet_id} has no webhook"))
+}
+
+/// Queues a `purchase.confirmed` or `purchase.failed` event for every vendor
+/// of a settled order with a webhook, carrying the items it sold.
+pub fn enqueue(webhooks: &SharedWebhooks, order: &Order) -> anyhow::Result<()> {
+    let event = match order.status {
+        PurchaseStatus::Confirmed => "purchase.confirmed",
+        PurchaseStatus::Failed => "purchase.failed",
+        PurchaseStatus::Pending => return Ok(()),
+    };
+    let mut vendors: Vec<_> = order.items.iter().map(|item| &item.vendor).collect();
+    vendors.sort();
+    vendors.dedup();
+    let now = purchases::now();
+    for vendor in vendors {
+        if webhooks.get_webhook(vendor)?.is_none() {
+            continue;
+        }
+        let items: Vec<_> = order
+            .items
+            .iter()
+            .filter(|item| item.vendor == *vendor)
+            .collect();
+        let payload = json!({
+            "type": event,
+            "created_at": now,
+            "order_id": order.id,
+            "buyer": order.buyer,
+            "signature": order.signature,
+            "status": order.status,
+            "error": order.error,
+            "items": items,
+        });
+        webhooks.insert_delivery(&Delivery {
+            id: 0,
+            created_at: now,
+            vendor: vendor.clone(),
+            event: event.into(),
+            order_id: order.id,
+            payload,
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now,
+            response_status: None,
+            error: None,
+        })?;
+    }
+    Ok(())
+}
+
+/// Attempts the due deliveries every second, concurrently, and at most
+/// [`PER_VENDOR`] at a time per vendor, the others waiting for the next poll.
+pub async fn run(webhooks: SharedWebhooks, retries: Retries) {
+    let client = match reqwest::Client::builder().timeout(retries.timeout).build() {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("webhooks disabled, failed to build http client: {e}");
+            return;
+        }
+    };
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        ticker.tick().await;
+        let due = match webhooks.due_deliveries(purchases::now(), PER_VENDOR, BATCH) {
+            Ok(due) => due,
+            Err(e) => {
+                warn!("failed to read webhook deliveries: {e}");
+                continue;
+            }
+        };
+        stream::iter(due)
+            .for_each_concurrent(None, |delivery| async {
+                if let Err(e) = attempt(&client, &webhooks, delivery, retries).await {
+                    warn!("failed to record webhook delivery: {e}");
+                }
+            })
+            .await;
+    }
+}
+
+async fn attempt(
+    client: &reqwest::Client,
+    webhooks: &SharedWebhooks,
+    mut delivery: Delivery,
+    retries: Retries,
+) -> anyhow::Result<()> {
+    delivery.attempts += 1;
+    delivery.response_status = None;
+    let outcome = match webhooks.get_webhook(&delivery.vendor)? {
+        Some(webhook) => send(client, &webhook, &delivery).await,
+        None => Err("the vendor no longer has a webhook".into()),
+    };
+    match outcome {
+        Ok(status) => {
+            delivery.status = DeliveryStatus::Delivered;
+            delivery.response_status = Some(status);
+            delivery.error = None;
+        }
+        Err(Failure { status, error }) => {
+            delivery.response_status = status;
+            delivery.error = Some(error);
+            if delivery.attempts >= retries.max_attempts {
+                warn!(
+                    "webhook delivery {} is dead after {} attempts",
+                    delivery.id, delivery.attempts
+                );
+                delivery.status = DeliveryStatus::Dead;
+            } else {
+                delivery.next_attempt_at = purchases::now()
+                    + retry::backoff(retries.backoff, retries.max_backoff, delivery.attempts)
+                        .as_secs() as i64;
+            }
+        }
+    }
+    webhooks.update_delivery(&delivery)
+}
+
+struct Failure {
+    status: Option<u16>,
+    error: String,
+}
+
+impl From<&str> for Failure {
+    fn from(error: &str) -> Self {
+        Self {
+            status: None,
+            error: error.into(),
+        }
+    }
+}
+
+/// Posts the payload of a delivery, signed with the webhook secret. Receivers
+/// check `X-Webhook-Signature`, `sha256=` followed by the hex encoded
+/// HMAC-SHA256 of `<X-Webhook-Timestamp>.<body>`, and may deduplicate
+/// retries on `X-Webhook-Id`.
+async fn send(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    delivery: &Delivery,
+) -> Result<u16, Failure> {
+    let body = delivery.payload.to_string();
+    let timestamp = purchases::now();
+    let response = client
+        .post(&webhook.url)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Id", delivery.id)
+        .header("X-Webhook-Event", &delivery.event)
+        .header("X-Webhook-Timestamp", timestamp)
+        .header(
+            "X-Webhook-Signature",
+            format!("sha256={}", sign(&webhook.secret, timestamp, &body)),
+        )
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| Failure {
+            status: None,
+            error: e.to_string(),
+        })?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Failure {
+            status: Some(status.as_u16()),
+            error: format!("webhook answered {status}"),
+        });
+    }
+    Ok(status.as_u16())
+}
+
+fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{Auth, API_KEY_HEADER},
+        store::MemoryStore,
+    };
+    use axum::{body::Bytes, http::HeaderMap, Extension, Server};
+    use std::{collections::VecDeque, net::SocketAddr, sync::Mutex};
+
+    const SECRET: &str = "secret";
+    const ADMIN_TOKEN: &str = "admin-token";
+
+    /// A webhook endpoint answering the queued statuses in turn, then 200,
+    /// and keeping the requests it received.
+    #[derive(Clone, Default)]
+    struct Receiver {
+        statuses: Arc<Mutex<VecDeque<u16>>>,
+        requests: Arc<Mutex<Vec<(HeaderMap, Bytes)>>>,
+    }
+
+    async fn receive(
+        State(receiver): State<Receiver>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        receiver.requests.lock().unwrap().push((headers, body));
+        let status = receiver.statuses.lock().unwrap().pop_front();
+        StatusCode::from_u16(status.unwrap_or(200)).unwrap()
+    }
+
+    async fn serve(router: Router) -> SocketAddr {
+        let server =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(router.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    /// A store with a webhook pointing at a new receiver, and a delivery
+    /// queued for it.
+    async fn setup(statuses: &[u16]) -> (Arc<MemoryStore>, Receiver, Delivery) {
+        let receiver = Receiver::default();
+        receiver.statuses.lock().unwrap().extend(statuses);
+        let addr = serve(
+            Router::new()
+                .route("/hook", post(receive))
+                .with_state(receiver.clone()),
+        )
+        .await;
+        let store = Arc::new(MemoryStore::default());
+        store
+            .set_webhook(&Webhook {
+                vendor: "vendor".into(),
+                url: format!("http://{addr}/hook"),
+                secret: SECRET.into(),
+            })
+            .unwrap();
+        let now = purchases::now();
+        let mut delivery = Delivery {
+            id: 0,
+            created_at: now,
+            vendor: "vendor".into(),
+            event: "purchase.confirmed".into(),
+            order_id: 1,
+            payload: json!({ "type": "purchase.confirmed", "order_id": 1 }),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now,
+            response_status: None,
+            error: None,
+        };
+        delivery.id = store.insert_delivery(&delivery).unwrap();
+        (store, receiver, delivery)
+    }
+
+    fn retries(max_attempts: u32) -> Retries {
+        Retries {
+            max_attempts,
+            ..Retries::default()
+        }
+    }
+
+    async fn deliver(store: &Arc<MemoryStore>, id: u64, retries: Retries) -> Delivery {
+        let webhooks: SharedWebhooks = store.clone();
+        let delivery = store.get_delivery(id).unwrap().unwrap();
+        attempt(&reqwest::Client::new(), &webhooks, delivery, retries)
+            .await
+            .unwrap();
+        store.get_delivery(id).unwrap().unwrap()
+    }
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        assert_eq!(
+            sign(SECRET, 1_700_000_000, r#"{"type":"purchase.confirmed"}"#),
+            "285b0fa40dcbcdae7f9a128ca8edefe8ccef4b84fce4efafb3f8187207379861"
+        );
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_events() {
+        let (store, receiver, delivery) = setup(&[]).await;
+        let delivered = deliver(&store, delivery.id, retries(3)).await;
+        assert_eq!(delivered.status, DeliveryStatus::Delivered);
+        assert_eq!(delivered.attempts, 1);
+        assert_eq!(delivered.response_status, Some(200));
+
+        let requests = receiver.requests.lock().unwrap();
+        let [(headers, body)] = &requests[..] else {
+            panic!("expected a single request, got {}", requests.len());
+        };
+        let header = |name| headers[name].to_str().unwrap();
+        assert_eq!(header("x-webhook-id"), delivery.id.to_string());
+        assert_eq!(header("x-webhook-event"), "purchase.confirmed");
+        let signature = header("x-webhook-signature")
+            .strip_prefix("sha256=")
+            .unwrap();
+        let signed = [header("x-webhook-timestamp").as_bytes(), b".", body].concat();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(&signed);
+        let expected: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        assert_eq!(signature, expected);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(body).unwrap(),
+            delivery.payload
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_failed_deliveries_with_backoff() {
+        let (store, receiver, delivery) = setup(&[500, 502]).await;
+        let retries = retries(5);
+
+        let before = purchases::now();
+        let failed = deliver(&store, delivery.id, retries).await;
+        assert_eq!(failed.status, DeliveryStatus::Pending);
+        assert_eq!(failed.attempts, 1);
+        assert_eq!(failed.response_status, Some(500));
+        assert!(failed.error.is_some());
+        let backoff = retries.backoff.as_secs() as i64;
+        assert!((before + backoff..=purchases::now() + backoff).contains(&failed.next_attempt_at));
+        assert!(store
+            .due_deliveries(before, PER_VENDOR, BATCH)
+            .unwrap()
+            .is_empty());
+
+        let before = purchases::now();
+        let failed = deliver(&store, delivery.id, retries).await;
+        assert_eq!(failed.attempts, 2);
+        assert_eq!(failed.response_status, Some(502));
+        assert!((before + 2 * backoff..=purchases::now() + 2 * backoff)
+            .contains(&failed.next_attempt_at));
+
+        let delivered = deliver(&store, delivery.id, retries).await;
+        assert_eq!(delivered.status, DeliveryStatus::Delivered);
+        assert_eq!(delivered.attempts, 3);
+        assert_eq!(delivered.error, None);
+        assert_eq!(receiver.requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn dead_letters_after_max_attempts() {
+        let (store, _, delivery) = setup(&[503, 503, 503]).await;
+        assert_eq!(
+            deliver(&store, delivery.id, retries(2)).await.status,
+            DeliveryStatus::Pending
+        );
+        let dead = deliver(&store, delivery.id, retries(2)).await;
+        assert_eq!(dead.status, DeliveryStatus::Dead);
+        assert_eq!(dead.attempts, 2);
+        assert_eq!(dead.response_status, Some(503));
+        assert!(store
+            .due_deliveries(i64::MAX, PER_VENDOR, BATCH)
+            .unwrap()
+            .is_empty());
+        let filter = DeliveryFilter {
+            status: Some(DeliveryStatus::Dead),
+            ..DeliveryFilter::default()
+        };
+        assert_eq!(store.list_deliveries(&filter).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_dead_deliveries_on_request() {
+        let (store, _, delivery) = setup(&[503]).await;
+        let dead = deliver(&store, delivery.id, retries(1)).await;
+        assert_eq!(dead.status, DeliveryStatus::Dead);
+
+        let auth = Auth::new(
+            &[auth::hash(ADMIN_TOKEN)],
+            &[auth::hash("client-token")],
+            false,
+        )
+        .unwrap();
+        let addr = serve(
+            router(WebhookState {
+                vendors: store.clone(),
+                webhooks: store.clone(),
+            })
+            .layer(Extension(Arc::new(auth))),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let retry = |id: u64, token: Option<&str>| {
+            let request = client.post(format!("http://{addr}/webhooks/deliveries/{id}/retry"));
+            match token {
+                Some(token) => request.header(API_KEY_HEADER, token),
+                None => request,
+            }
+            .send()
+        };
+
+        let response = retry(delivery.id, None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = retry(delivery.id + 1, Some(ADMIN_TOKEN)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = retry(delivery.id, Some(ADMIN_TOKEN)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let queued = store.get_delivery(delivery.id).unwrap().unwrap();
+        assert_eq!(queued.status, DeliveryStatus::Pending);
+        assert_eq!(queued.attempts, 0);
+        assert_eq!(
+            store
+                .due_deliveries(purchases::now(), PER_VENDOR, BATCH)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let response = retry(delivery.id, Some(ADMIN_TOKEN)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let delivered = deliver(&store, delivery.id, retries(1)).await;
+        assert_eq!(delivered.status, DeliveryStatus::Delivered);
+    }
+}